@@ -0,0 +1,40 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+extern crate protoc_rust;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let dest_path = Path::new(&out_dir).join("protos");
+    fs::create_dir_all(&dest_path).unwrap();
+
+    protoc_rust::Codegen::new()
+        .out_dir(&dest_path)
+        .inputs(&["protos/snowball_message.proto"])
+        .includes(&["protos"])
+        .run()
+        .expect("Unable to generate Rust code from the protos");
+
+    fs::write(dest_path.join("mod.rs"), "pub mod snowball_message;\n")
+        .expect("Unable to write the protos module");
+
+    println!("cargo:rerun-if-changed=protos/snowball_message.proto");
+}
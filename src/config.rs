@@ -1,15 +1,106 @@
 //! Initial configuration for a Snowball node
 
 use std::collections::HashMap;
+use std::fmt;
 use std::time::Duration;
 
+use safe_crypto::PublicSignKey;
 use sawtooth_sdk::consensus::{
     engine::{BlockId, PeerId},
     service::Service,
 };
 
+use crate::signing::get_public_keys_from_setting;
 use crate::timing::retry_until_ok;
 
+/// Strategies for choosing which member proposes the block of a given instance
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum ProposerSchedule {
+    /// Rotate over the member list, one proposer per sequence number
+    RoundRobin,
+    /// Pick the member indexed by the hash of the block the proposal builds on
+    PreviousBlockHash,
+}
+
+/// Variants of the protocol, differing in the update rule of each query round
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum SnowballVariant {
+    /// Adopt every majority, and decide after a fixed number of rounds
+    Slush,
+    /// Decide after `beta` consecutive rounds confirming the preference
+    Snowflake,
+    /// Prefer the color with the most successful rounds, and decide after
+    /// `beta` consecutive rounds confirming the same color
+    Snowball,
+}
+
+/// Strategies for choosing the peers queried in each round
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum SamplingStrategy {
+    /// Every other member is equally likely to be sampled
+    Uniform,
+    /// Like `Uniform`, skipping members which still owe a response
+    ExcludeAwaited,
+    /// Members are sampled in proportion to their stake
+    Stake,
+    /// Members are sampled in proportion to their reputation
+    Reputation,
+}
+
+/// How an instance which reaches the maximum number of rounds gets decided.
+/// Before failing or breaking the tie, the node asks its peers for their
+/// decisions and adopts one reported by `alfa_confidence` of them. The policy
+/// only applies when none is reported within the hang timeout, and it is local
+/// to the node: escalations trade safety for liveness.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum EscalationPolicy {
+    /// Decide `KO`, failing every candidate
+    Fail,
+    /// Restart the vote on the same candidates from the validator's verdicts;
+    /// no new block is proposed
+    Restart,
+    /// Decide the color with the most successful rounds. Blocks win ties
+    /// against `KO`, and the smallest block id breaks ties between blocks.
+    TieBreak,
+}
+
+/// Encodings of the messages sent to peers
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum WireFormat {
+    /// Legacy encoding, understood by every version of the engine
+    Json,
+    /// Protobuf encoding of `protos/snowball_message.proto`
+    Protobuf,
+}
+
+impl fmt::Display for EscalationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                EscalationPolicy::Fail => "fail",
+                EscalationPolicy::Restart => "restart",
+                EscalationPolicy::TieBreak => "tie_break",
+            },
+        )
+    }
+}
+
+impl fmt::Display for SnowballVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SnowballVariant::Slush => "slush",
+                SnowballVariant::Snowflake => "snowflake",
+                SnowballVariant::Snowball => "snowball",
+            },
+        )
+    }
+}
+
 /// Contains the initial configuration loaded from on-chain settings and local configuration. The
 /// `members` list is required; all other settings are optional (defaults used in their absence)
 #[derive(Debug)]
@@ -20,18 +111,80 @@ pub struct SnowballConfig {
     // Alfa: majority threshold
     pub alfa: u64,
 
+    /// Votes needed in a round to change the preferred color
+    pub alfa_preference: u64,
+
+    /// Votes needed in a round to increase the confidence in a color
+    pub alfa_confidence: u64,
+
     // Beta: confidence threshold
     pub beta: u64,
 
     // sample size
     pub k: u64,
 
+    /// Recompute k, alfa and beta from the membership size for each instance
+    pub adaptive: bool,
+
+    /// Bounds of the adaptive sample size
+    pub adaptive_k_min: u64,
+    pub adaptive_k_max: u64,
+
+    /// Bounds of the adaptive confidence threshold
+    pub adaptive_beta_min: u64,
+    pub adaptive_beta_max: u64,
+
+    /// Update rule applied at the end of each query round
+    pub variant: SnowballVariant,
+
+    /// How the proposer of each instance is chosen among the members
+    pub proposer_schedule: ProposerSchedule,
+
+    /// Weight of each member when sampling peers by stake
+    pub stake: HashMap<PeerId, u64>,
+
+    /// How the peers queried in each round are chosen
+    pub sampler: SamplingStrategy,
+
+    /// Keys verifying the messages of each member; messages are only checked
+    /// when the map is not empty
+    pub public_keys: HashMap<PeerId, PublicSignKey>,
+
+    /// Local file holding the keypair of this node, generated if missing
+    pub signing_key_path: Option<String>,
+
+    /// Maximum number of instances running at the same time
+    pub window: u64,
+
+    /// Rounds after which an undecided instance is escalated, 0 for no limit
+    pub max_rounds: u64,
+
+    /// How instances reaching `max_rounds` are decided
+    pub escalation: EscalationPolicy,
+
+    /// Encoding of the messages sent to peers; both are accepted
+    pub wire_format: WireFormat,
+
+    /// Whether blocks whose consensus field is the bare summary, as published
+    /// by nodes which predate the consensus payload, are accepted
+    pub legacy_blocks: bool,
+
     /// How long to wait in between trying to publish blocks
     pub block_publishing_delay: Duration,
 
     /// How long to wait before deciding a process is hung
     pub hang_timeout: Duration,
 
+    /// How long to wait for the next block before voting on a new proposer
+    pub idle_timeout: Duration,
+
+    /// How long to wait for the validator to judge a block before abstaining
+    pub validation_timeout: Duration,
+
+    /// How long a query about an instance which didn't start yet is held
+    /// before answering that it is unavailable
+    pub deferred_request_timeout: Duration,
+
     /// How long to wait for an update to arrive from the validator
     pub update_recv_timeout: Duration,
 
@@ -68,10 +221,31 @@ impl SnowballConfig {
         SnowballConfig {
             members: Vec::new(),
             alfa: 0,
+            alfa_preference: 0,
+            alfa_confidence: 0,
             beta: 0,
             k: 0,
+            adaptive: false,
+            adaptive_k_min: 1,
+            adaptive_k_max: 0,
+            adaptive_beta_min: 0,
+            adaptive_beta_max: 0,
+            variant: SnowballVariant::Snowball,
+            proposer_schedule: ProposerSchedule::RoundRobin,
+            stake: HashMap::new(),
+            sampler: SamplingStrategy::Uniform,
+            public_keys: HashMap::new(),
+            signing_key_path: None,
+            window: 1,
+            max_rounds: 0,
+            escalation: EscalationPolicy::TieBreak,
+            wire_format: WireFormat::Json,
+            legacy_blocks: true,
             block_publishing_delay: Duration::from_millis(5000),
             hang_timeout: Duration::from_millis(3000),
+            idle_timeout: Duration::from_millis(30000),
+            validation_timeout: Duration::from_millis(2000),
+            deferred_request_timeout: Duration::from_millis(1000),
             update_recv_timeout: Duration::from_millis(10),
             exponential_retry_base: Duration::from_millis(100),
             exponential_retry_max: Duration::from_millis(60000),
@@ -95,7 +269,43 @@ impl SnowballConfig {
     /// + `sawtooth.consensus.algorithm.alfa` (required)
     /// + `sawtooth.consensus.algorithm.beta` (required)
     /// + `sawtooth.consensus.algorithm.k` (required)
+    /// + `sawtooth.consensus.algorithm.alfa_preference` (optional, default `alfa`)
+    /// + `sawtooth.consensus.algorithm.alfa_confidence` (optional, default `alfa`, raised to
+    ///   `alfa_preference` when lower)
+    /// + `sawtooth.consensus.algorithm.adaptive` (optional, default false); each instance then
+    ///   samples as many peers as available within `[adaptive_k_min, adaptive_k_max]`, scales
+    ///   both alfas to keep their ratio to `k`, and sets beta to the bit length of the number of
+    ///   peers within `[adaptive_beta_min, adaptive_beta_max]`
+    /// + `sawtooth.consensus.algorithm.adaptive_k_min` (optional, default 1)
+    /// + `sawtooth.consensus.algorithm.adaptive_k_max` (optional, default `k`)
+    /// + `sawtooth.consensus.algorithm.adaptive_beta_min` (optional, default `beta`)
+    /// + `sawtooth.consensus.algorithm.adaptive_beta_max` (optional, default `beta`)
     /// + `sawtooth.consensus.algorithm.block_publishing_delay` (optional, default 10000 ms)
+    /// + `sawtooth.consensus.algorithm.idle_timeout` (optional, default 30000 ms); members vote
+    ///   on a replacement proposer when no block arrives for this long
+    /// + `sawtooth.consensus.algorithm.proposer_schedule` (optional, `round_robin` or
+    ///   `block_hash`, default `round_robin`)
+    /// + `sawtooth.consensus.algorithm.validation_timeout` (optional, default 2000 ms)
+    /// + `sawtooth.consensus.algorithm.deferred_request_timeout` (optional, default 1000 ms)
+    /// + `sawtooth.consensus.algorithm.window` (optional, default 1)
+    /// + `sawtooth.consensus.algorithm.variant` (optional, `slush`, `snowflake` or `snowball`,
+    ///   default `snowball`); Slush runs `beta` rounds
+    /// + `sawtooth.consensus.algorithm.max_rounds` (optional, default 0 for no limit)
+    /// + `sawtooth.consensus.algorithm.escalation` (optional, `fail`, `restart` or `tie_break`,
+    ///   default `tie_break`); applied to instances reaching `max_rounds`. `restart` votes
+    ///   again on the same candidates, it doesn't propose a new block
+    /// + `sawtooth.consensus.algorithm.wire_format` (optional, `json` or `protobuf`, default
+    ///   `json`); messages in both formats are accepted, so networks upgrading from JSON can
+    ///   switch to `protobuf` once every node runs this version
+    /// + `sawtooth.consensus.algorithm.legacy_blocks` (optional, default true); whether blocks
+    ///   whose consensus field is `Snowball` followed by the bare summary are accepted, to be
+    ///   turned off once every node runs this version
+    /// + `sawtooth.consensus.algorithm.stake` (optional, JSON map of hex member ids to weights);
+    ///   members missing from the map are never sampled by stake
+    /// + `sawtooth.consensus.algorithm.sampler` (optional, `uniform`, `exclude_awaited`, `stake`
+    ///   or `reputation`, default `stake` when a stake table is set and `uniform` otherwise)
+    /// + `sawtooth.consensus.algorithm.public_keys` (optional, JSON map of hex member ids to hex
+    ///   public keys); once set, messages without a valid signature from their sender are dropped
     /// TODO: document byzantine params
     ///
     /// # Panics
@@ -116,9 +326,28 @@ impl SnowballConfig {
                         String::from("sawtooth.consensus.algorithm.block_publishing_delay"),
                         String::from("sawtooth.consensus.algorithm.idle_timeout"),
                         String::from("sawtooth.consensus.algorithm.alfa"),
+                        String::from("sawtooth.consensus.algorithm.alfa_preference"),
+                        String::from("sawtooth.consensus.algorithm.alfa_confidence"),
                         String::from("sawtooth.consensus.algorithm.beta"),
                         String::from("sawtooth.consensus.algorithm.k"),
+                        String::from("sawtooth.consensus.algorithm.adaptive"),
+                        String::from("sawtooth.consensus.algorithm.adaptive_k_min"),
+                        String::from("sawtooth.consensus.algorithm.adaptive_k_max"),
+                        String::from("sawtooth.consensus.algorithm.adaptive_beta_min"),
+                        String::from("sawtooth.consensus.algorithm.adaptive_beta_max"),
                         String::from("sawtooth.consensus.algorithm.hang_timeout"),
+                        String::from("sawtooth.consensus.algorithm.proposer_schedule"),
+                        String::from("sawtooth.consensus.algorithm.validation_timeout"),
+                        String::from("sawtooth.consensus.algorithm.deferred_request_timeout"),
+                        String::from("sawtooth.consensus.algorithm.window"),
+                        String::from("sawtooth.consensus.algorithm.variant"),
+                        String::from("sawtooth.consensus.algorithm.max_rounds"),
+                        String::from("sawtooth.consensus.algorithm.escalation"),
+                        String::from("sawtooth.consensus.algorithm.stake"),
+                        String::from("sawtooth.consensus.algorithm.wire_format"),
+                        String::from("sawtooth.consensus.algorithm.legacy_blocks"),
+                        String::from("sawtooth.consensus.algorithm.sampler"),
+                        String::from("sawtooth.consensus.algorithm.public_keys"),
                         String::from("sawtooth.byzantine.enabled"),
                         String::from("sawtooth.byzantine.parameter.max_churn_timeout"),
                         String::from("sawtooth.byzantine.parameter.churn_idx"),
@@ -143,6 +372,28 @@ impl SnowballConfig {
             .parse::<u64>()
             .expect("'sawtooth.consensus.algorithm.alfa' is empty; this setting must exist to use Snowball");
 
+        self.alfa_preference = self.alfa;
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.alfa_preference") {
+            if let Ok(setting_value) = setting.parse() {
+                self.alfa_preference = setting_value;
+            }
+        }
+
+        self.alfa_confidence = self.alfa;
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.alfa_confidence") {
+            if let Ok(setting_value) = setting.parse() {
+                self.alfa_confidence = setting_value;
+            }
+        }
+
+        if self.alfa_confidence < self.alfa_preference {
+            warn!(
+                "alfa_confidence {} is lower than alfa_preference {}, raising it",
+                self.alfa_confidence, self.alfa_preference
+            );
+            self.alfa_confidence = self.alfa_preference;
+        }
+
         self.beta = settings
             .get("sawtooth.consensus.algorithm.beta")
             .unwrap()
@@ -155,6 +406,39 @@ impl SnowballConfig {
             .parse::<u64>()
             .expect("'sawtooth.consensus.algorithm.k' is empty; this setting must exist to use Snowball");
 
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.adaptive") {
+            if let Ok(setting_value) = setting.parse() {
+                self.adaptive = setting_value;
+            }
+        }
+
+        self.adaptive_k_max = self.k;
+        self.adaptive_beta_min = self.beta;
+        self.adaptive_beta_max = self.beta;
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.adaptive_k_min") {
+            if let Ok(setting_value) = setting.parse() {
+                self.adaptive_k_min = setting_value;
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.adaptive_k_max") {
+            if let Ok(setting_value) = setting.parse() {
+                self.adaptive_k_max = setting_value;
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.adaptive_beta_min") {
+            if let Ok(setting_value) = setting.parse() {
+                self.adaptive_beta_min = setting_value;
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.adaptive_beta_max") {
+            if let Ok(setting_value) = setting.parse() {
+                self.adaptive_beta_max = setting_value;
+            }
+        }
+
         // Get durations
         merge_millis_setting_if_set(
             &settings,
@@ -168,6 +452,104 @@ impl SnowballConfig {
             "sawtooth.consensus.algorithm.hang_timeout",
         );
 
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.idle_timeout,
+            "sawtooth.consensus.algorithm.idle_timeout",
+        );
+
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.validation_timeout,
+            "sawtooth.consensus.algorithm.validation_timeout",
+        );
+
+        merge_millis_setting_if_set(
+            &settings,
+            &mut self.deferred_request_timeout,
+            "sawtooth.consensus.algorithm.deferred_request_timeout",
+        );
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.window") {
+            if let Ok(setting_value) = setting.parse::<u64>() {
+                self.window = setting_value.max(1);
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.variant") {
+            match setting.as_str() {
+                "slush" => self.variant = SnowballVariant::Slush,
+                "snowflake" => self.variant = SnowballVariant::Snowflake,
+                "snowball" => self.variant = SnowballVariant::Snowball,
+                _ => warn!("Unknown algorithm variant '{}', using {}", setting, self.variant),
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.max_rounds") {
+            if let Ok(setting_value) = setting.parse() {
+                self.max_rounds = setting_value;
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.escalation") {
+            match setting.as_str() {
+                "fail" => self.escalation = EscalationPolicy::Fail,
+                "restart" => self.escalation = EscalationPolicy::Restart,
+                "tie_break" => self.escalation = EscalationPolicy::TieBreak,
+                _ => warn!("Unknown escalation policy '{}', using {}", setting, self.escalation),
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.wire_format") {
+            match setting.as_str() {
+                "json" => self.wire_format = WireFormat::Json,
+                "protobuf" => self.wire_format = WireFormat::Protobuf,
+                _ => warn!("Unknown wire format '{}', using {:?}", setting, self.wire_format),
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.legacy_blocks") {
+            if let Ok(setting_value) = setting.parse() {
+                self.legacy_blocks = setting_value;
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.proposer_schedule") {
+            match setting.as_str() {
+                "round_robin" => self.proposer_schedule = ProposerSchedule::RoundRobin,
+                "block_hash" => self.proposer_schedule = ProposerSchedule::PreviousBlockHash,
+                _ => warn!("Unknown proposer schedule '{}', using {:?}", setting, self.proposer_schedule),
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.stake") {
+            self.stake = get_stake_from_setting(setting).unwrap_or_else(|err| {
+                warn!("Unable to parse 'sawtooth.consensus.algorithm.stake', sampling uniformly: {}", err);
+                HashMap::new()
+            });
+        }
+
+        if !self.stake.is_empty() {
+            self.sampler = SamplingStrategy::Stake;
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.sampler") {
+            match setting.as_str() {
+                "uniform" => self.sampler = SamplingStrategy::Uniform,
+                "exclude_awaited" => self.sampler = SamplingStrategy::ExcludeAwaited,
+                "stake" => self.sampler = SamplingStrategy::Stake,
+                "reputation" => self.sampler = SamplingStrategy::Reputation,
+                _ => warn!("Unknown sampler '{}', using {:?}", setting, self.sampler),
+            }
+        }
+
+        if let Some(setting) = settings.get("sawtooth.consensus.algorithm.public_keys") {
+            self.public_keys = get_public_keys_from_setting(setting).unwrap_or_else(|err| {
+                warn!("Unable to parse 'sawtooth.consensus.algorithm.public_keys', not verifying signatures: {}", err);
+                HashMap::new()
+            });
+        }
+
         // Configure byzantine parameters
         if let Some(setting) = settings.get("sawtooth.byzantine.enabled") {
             if let Ok(setting_value) = setting.parse() {
@@ -255,6 +637,20 @@ fn merge_millis_setting_if_set(
     )
 }
 
+/// Parse the stake table, a JSON map of hex encoded member ids to weights
+fn get_stake_from_setting(setting: &str) -> Result<HashMap<PeerId, u64>, String> {
+    let stake: HashMap<String, u64> = serde_json::from_str(setting).map_err(|err| err.to_string())?;
+
+    stake
+        .into_iter()
+        .map(|(id, weight)| {
+            hex::decode(&id)
+                .map(|peer_id| (peer_id, weight))
+                .map_err(|err| format!("invalid member id {}: {:?}", id, err))
+        })
+        .collect()
+}
+
 /// Get the list of Snowball members as a Vec<PeerId> from settings
 ///
 /// # Panics
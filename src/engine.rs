@@ -1,7 +1,6 @@
 use std::fmt::{self, Write};
 use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::time::{Duration, Instant};
-use std::str;
 
 use crate::timing;
 use crate::storage::get_storage;
@@ -87,6 +86,10 @@ impl Engine for SnowballEngine {
 
             node.handle_queue(state);
 
+            node.handle_pending_validation(state);
+
+            node.handle_deferred_requests(state);
+
             match handle_update(&mut node, incoming_message, state) {
                 Ok(again) => {
                     if !again {
@@ -99,6 +102,12 @@ impl Engine for SnowballEngine {
 
             node.handle_unresponsive_peers(state);
 
+            node.retry_unsampled_rounds(state);
+
+            node.check_idle(state);
+
+            node.check_escalations(state);
+
             block_publishing_ticker.tick(|| node.try_publish(state));
 
             if Instant::now().duration_since(timestamp_log) > Duration::from_millis(4500) {
@@ -153,11 +162,16 @@ fn handle_update(
     match incoming_message {
         Ok(Update::BlockNew(block)) => node.on_block_new(block, state),
         Ok(Update::BlockValid(block_id)) => node.on_block_valid(block_id, state),
-        Ok(Update::BlockInvalid(block_id)) => node.on_block_invalid(block_id),
+        Ok(Update::BlockInvalid(block_id)) => node.on_block_invalid(block_id, state),
         Ok(Update::BlockCommit(block_id)) => node.on_block_commit(block_id, state),
         Ok(Update::PeerMessage(message, sender_id)) => {
-            let content_string = str::from_utf8(message.content.as_ref()).unwrap();
-            let payload: SnowballMessage = serde_json::from_str(content_string).unwrap();
+            let payload = match SnowballMessage::from_bytes(message.content.as_ref()) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    warn!("Unable to decode message from {}: {}", hex::encode(&sender_id), err);
+                    return Ok(true);
+                }
+            };
             // info!("Message content: {}", payload);
             node.on_peer_message(message.header.message_type.as_ref(), &sender_id, payload, state);
             return Ok(true);
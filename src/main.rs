@@ -32,6 +32,9 @@ pub mod storage;
 pub mod state;
 pub mod node;
 pub mod message;
+pub mod protos;
+pub mod sampler;
+pub mod signing;
 
 use std::process;
 
@@ -49,6 +52,8 @@ fn main() {
         (about: "Snowball Consensus Engine (Rust)")
         (@arg connect: -C --connect +takes_value
          "connection endpoint for validator")
+        (@arg signing_key: -k --("signing-key") +takes_value
+         "file holding the keypair signing peer messages, generated if missing")
         (@arg verbose: -v --verbose +multiple
          "increase output verbosity"))
     .get_matches();
@@ -86,7 +91,8 @@ fn main() {
 
     info!("Sawtooth Snowball Engine ({})", env!("CARGO_PKG_VERSION"));
 
-    let snowball_config = config::SnowballConfig::default();
+    let mut snowball_config = config::SnowballConfig::default();
+    snowball_config.signing_key_path = matches.value_of("signing_key").map(String::from);
 
     let snowball_engine = SnowballEngine::new(snowball_config);
 
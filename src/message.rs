@@ -1,25 +1,298 @@
 use std::fmt;
+
+use protobuf::{Message, RepeatedField};
+
 use crate::state::{ByzantineParameters};
-use crate::config::{SnowballConfig};
+use crate::config::{SnowballConfig, WireFormat};
+use crate::protos::snowball_message as proto;
 
-#[derive(Serialize, Deserialize)]
+/// Version of the protobuf wire format of peer messages
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct SnowballMessage {
     pub message_type: String,
     pub seq_num: u64,
     pub vote: u8,
+    // Fields added after the first release default when missing, so that
+    // messages of older nodes still decode
+    #[serde(default)]
+    pub block_id: Vec<u8>,
+    #[serde(default)]
+    pub decisions: Vec<SyncDecision>,
+    #[serde(default)]
+    pub view: u64,
+    /// Newest block preferred by the sender of a response
+    #[serde(default)]
+    pub tip: Vec<u8>,
     pub nonce: Vec<u8>,
+    /// Signature of the sender, see `signed_bytes`
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    #[serde(default)]
+    pub round_id: u64,
+    #[serde(default)]
+    pub query_id: u64,
+}
+
+/// Identifies a query about an instance; answers echo it so that they can be
+/// matched with the round that asked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QueryId {
+    pub round_id: u64,
+    pub query_id: u64,
+}
+
+impl fmt::Display for QueryId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "round {}, query {}", self.round_id, self.query_id)
+    }
+}
+
+/// Messages exchanged by Snowball nodes, each kind with its own payload
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerMessage {
+    /// Query for the preference on an instance
+    Request { seq_num: u64, query: QueryId },
+    /// Preference of the sender on an instance, and the newest block it prefers
+    Response { seq_num: u64, query: QueryId, vote: u8, block_id: Vec<u8>, tip: Vec<u8> },
+    /// The sender can't answer a query about an instance
+    Unavailable { seq_num: u64, query: QueryId },
+    /// Query for the view preferred for an instance whose proposer is silent
+    FailoverRequest { seq_num: u64, view: u64 },
+    /// View preferred by the sender for an instance
+    FailoverResponse { seq_num: u64, view: u64 },
+    /// Query for the decisions taken from a sequence number on
+    SyncRequest { seq_num: u64 },
+    /// Contiguous decisions taken by the sender
+    SyncResponse { seq_num: u64, decisions: Vec<SyncDecision> },
+}
+
+/// Raised when a peer message can't be decoded
+#[derive(Debug)]
+pub enum MessageError {
+    /// The message type is not known to this version of the engine
+    UnknownType(String),
+    /// The type in the message header differs from the one in its body
+    TypeMismatch { header: String, body: String },
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageError::UnknownType(message_type) => write!(f, "unknown message type {}", message_type),
+            MessageError::TypeMismatch { header, body } => {
+                write!(f, "header type {} doesn't match body type {}", header, body)
+            }
+        }
+    }
+}
+
+impl PeerMessage {
+    /// Type of the message, sent in both the header and the body
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            PeerMessage::Request { .. } => "request",
+            PeerMessage::Response { .. } => "response",
+            PeerMessage::Unavailable { .. } => "unavailable",
+            PeerMessage::FailoverRequest { .. } => "failover_request",
+            PeerMessage::FailoverResponse { .. } => "failover_response",
+            PeerMessage::SyncRequest { .. } => "sync_request",
+            PeerMessage::SyncResponse { .. } => "sync_response",
+        }
+    }
+
+    /// Build the typed message out of the type of the header and the body
+    pub fn decode(header_type: &str, payload: SnowballMessage) -> Result<Self, MessageError> {
+        if payload.message_type != header_type {
+            return Err(MessageError::TypeMismatch {
+                header: String::from(header_type),
+                body: payload.message_type,
+            });
+        }
+
+        let seq_num = payload.seq_num;
+        let query = QueryId {
+            round_id: payload.round_id,
+            query_id: payload.query_id,
+        };
+        match header_type {
+            "request" => Ok(PeerMessage::Request { seq_num, query }),
+            "response" => Ok(PeerMessage::Response {
+                seq_num,
+                query,
+                vote: payload.vote,
+                block_id: payload.block_id,
+                tip: payload.tip,
+            }),
+            "unavailable" => Ok(PeerMessage::Unavailable { seq_num, query }),
+            "failover_request" => Ok(PeerMessage::FailoverRequest { seq_num, view: payload.view }),
+            "failover_response" => Ok(PeerMessage::FailoverResponse { seq_num, view: payload.view }),
+            "sync_request" => Ok(PeerMessage::SyncRequest { seq_num }),
+            "sync_response" => Ok(PeerMessage::SyncResponse { seq_num, decisions: payload.decisions }),
+            _ => Err(MessageError::UnknownType(String::from(header_type))),
+        }
+    }
+
+    /// Build the body sent on the wire
+    pub fn into_payload(self) -> SnowballMessage {
+        let mut payload = SnowballMessage::new();
+        payload.message_type = String::from(self.message_type());
+        match self {
+            PeerMessage::Request { seq_num, query } | PeerMessage::Unavailable { seq_num, query } => {
+                payload.seq_num = seq_num;
+                payload.round_id = query.round_id;
+                payload.query_id = query.query_id;
+            }
+            PeerMessage::SyncRequest { seq_num } => payload.seq_num = seq_num,
+            PeerMessage::Response { seq_num, query, vote, block_id, tip } => {
+                payload.seq_num = seq_num;
+                payload.round_id = query.round_id;
+                payload.query_id = query.query_id;
+                payload.vote = vote;
+                payload.block_id = block_id;
+                payload.tip = tip;
+            }
+            PeerMessage::FailoverRequest { seq_num, view } | PeerMessage::FailoverResponse { seq_num, view } => {
+                payload.seq_num = seq_num;
+                payload.view = view;
+            }
+            PeerMessage::SyncResponse { seq_num, decisions } => {
+                payload.seq_num = seq_num;
+                payload.decisions = decisions;
+            }
+        }
+        payload
+    }
+}
+
+/// Decision taken for a sequence number, shared with peers catching up
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SyncDecision {
+    pub seq_num: u64,
+    pub vote: u8,
+    pub block_id: Vec<u8>,
+}
+
+/// Version of the consensus payload written in the blocks
+pub const CONSENSUS_VERSION: u8 = 1;
+
+/// Prefix of the consensus payload of every Snowball block
+pub const CONSENSUS_PREFIX: &[u8] = b"Snowball";
+
+/// Consensus payload of a block: the `Snowball` prefix followed by this
+/// structure encoded as JSON, with byte fields written as hex strings
+#[derive(Serialize, Deserialize)]
+pub struct ConsensusPayload {
+    pub version: u8,
+    /// Summary of the block contents, as computed by the validator
+    #[serde(with = "hex_bytes")]
+    pub summary: Vec<u8>,
+    /// Evidence of the decisions taken by the proposer of the block which no
+    /// committed block carried yet, by sequence number. A block is decided
+    /// after it is built, so its own certificate only appears in a later block.
+    pub certificates: Vec<FinalityCertificate>,
+}
+
+impl ConsensusPayload {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut consensus = Vec::from(CONSENSUS_PREFIX);
+        consensus.extend(serde_json::to_vec(self).unwrap());
+        consensus
+    }
+
+    pub fn decode(consensus: &[u8]) -> Result<Self, String> {
+        if !consensus.starts_with(CONSENSUS_PREFIX) {
+            return Err(String::from("missing Snowball prefix"));
+        }
+        serde_json::from_slice(&consensus[CONSENSUS_PREFIX.len()..]).map_err(|err| err.to_string())
+    }
+}
+
+/// Why a block was accepted: the outcome of the last round of its instance
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FinalityCertificate {
+    pub seq_num: u64,
+    #[serde(with = "hex_bytes")]
+    pub block_id: Vec<u8>,
+    pub block_num: u64,
+    /// 1 when the block was committed, 0 when every candidate was failed
+    pub vote: u8,
+    /// Consecutive successful rounds backing the decision
+    pub confidence: u64,
+    /// Rounds run by the instance
+    pub round: u64,
+    /// Votes collected in the last round
+    pub response_buffer: Vec<CertifiedVote>,
+    /// Peers which answered in the last round
+    #[serde(with = "hex_bytes_list")]
+    pub responders: Vec<Vec<u8>>,
+}
+
+/// Number of peers casting the same vote in a round
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CertifiedVote {
+    pub vote: u8,
+    #[serde(with = "hex_bytes")]
+    pub block_id: Vec<u8>,
+    pub count: u64,
+}
+
+/// Writes a byte field as a hex string rather than an array of numbers
+mod hex_bytes {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        hex::decode(encoded).map_err(|err| D::Error::custom(format!("{:?}", err)))
+    }
+}
+
+/// Writes a list of byte fields as hex strings
+mod hex_bytes_list {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|encoded| hex::decode(encoded).map_err(|err| D::Error::custom(format!("{:?}", err))))
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LogMessage {
     pub seq_num: u64,
     pub n_messages: u64,
+    pub n_unknown_messages: u64,
+    pub n_replayed_messages: u64,
+    pub n_late_responses: u64,
     pub n_members: u64,
+    pub total_stake: u64,
     pub elapsed_time: u128,
     pub block_id: String,
+    pub n_candidates: u64,
+    pub variant: String,
     pub alfa: u64,
+    pub alfa_preference: u64,
+    pub alfa_confidence: u64,
     pub beta: u64,
     pub k: u64,
+    pub adaptive: bool,
+    pub window: u64,
+    pub escalation: String,
+    pub escalations: u64,
+    pub abstentions: u64,
     pub order: u64,
     pub decision: u8,
     pub hang_timeout: u64,
@@ -32,12 +305,25 @@ impl LogMessage {
         LogMessage {
             seq_num: 0,
             n_messages: 0,
+            n_unknown_messages: 0,
+            n_replayed_messages: 0,
+            n_late_responses: 0,
             n_members: 0,
+            total_stake: 0,
             elapsed_time: 0,
             block_id: String::new(),
+            n_candidates: 0,
+            variant: String::new(),
             alfa: 0,
+            alfa_preference: 0,
+            alfa_confidence: 0,
             beta: 0,
             k: 0,
+            adaptive: false,
+            window: 0,
+            escalation: String::new(),
+            escalations: 0,
+            abstentions: 0,
             order: 0,
             decision: 0,
             hang_timeout: 0,
@@ -52,17 +338,231 @@ impl SnowballMessage {
             message_type: String::from("undefined"), 
             seq_num: 0, 
             vote: 0, 
-            nonce: Vec::new()
+            block_id: Vec::new(),
+            decisions: Vec::new(),
+            view: 0,
+            tip: Vec::new(),
+            nonce: Vec::new(),
+            signature: Vec::new(),
+            round_id: 0,
+            query_id: 0,
         }
     }
 }
 
+impl SnowballMessage {
+    /// Encode the message for the wire
+    pub fn to_bytes(&self, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Json => serde_json::to_vec(self).unwrap(),
+            WireFormat::Protobuf => self.to_proto().write_to_bytes().expect("Failed to encode message"),
+        }
+    }
+
+    /// Bytes covered by the signature: the protobuf encoding of the message
+    /// without its signature, whatever format it travelled in
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut message = self.to_proto();
+        message.clear_signature();
+        message.write_to_bytes().expect("Failed to encode message")
+    }
+
+    fn to_proto(&self) -> proto::SnowballMessage {
+        let decisions = self.decisions
+            .iter()
+            .map(|decision| {
+                let mut sync_decision = proto::SyncDecision::new();
+                sync_decision.set_seq_num(decision.seq_num);
+                sync_decision.set_vote(u32::from(decision.vote));
+                sync_decision.set_block_id(decision.block_id.clone());
+                sync_decision
+            })
+            .collect();
+
+        let mut message = proto::SnowballMessage::new();
+        message.set_msg_type(self.message_type.clone());
+        message.set_seq_num(self.seq_num);
+        message.set_vote(u32::from(self.vote));
+        message.set_nonce(self.nonce.clone());
+        message.set_block_id(self.block_id.clone());
+        message.set_decisions(RepeatedField::from_vec(decisions));
+        message.set_view(self.view);
+        message.set_tip(self.tip.clone());
+        message.set_protocol_version(PROTOCOL_VERSION);
+        message.set_signature(self.signature.clone());
+        message.set_round_id(self.round_id);
+        message.set_query_id(self.query_id);
+        message
+    }
+
+    /// Decode a message in either wire format. Legacy JSON messages start with
+    /// an opening brace, which is never the first byte of a protobuf message.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.first() == Some(&b'{') {
+            return serde_json::from_slice(bytes).map_err(|err| err.to_string());
+        }
+
+        let mut message = <proto::SnowballMessage as Message>::parse_from_bytes(bytes).map_err(|err| err.to_string())?;
+        if message.get_protocol_version() != PROTOCOL_VERSION {
+            return Err(format!("unsupported protocol version {}", message.get_protocol_version()));
+        }
+
+        let decisions = message
+            .take_decisions()
+            .into_iter()
+            .map(|mut decision| {
+                Ok(SyncDecision {
+                    seq_num: decision.get_seq_num(),
+                    vote: decode_vote(decision.get_vote())?,
+                    block_id: decision.take_block_id(),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(SnowballMessage {
+            message_type: message.take_msg_type(),
+            seq_num: message.get_seq_num(),
+            vote: decode_vote(message.get_vote())?,
+            block_id: message.take_block_id(),
+            decisions,
+            view: message.get_view(),
+            tip: message.take_tip(),
+            nonce: message.take_nonce(),
+            signature: message.take_signature(),
+            round_id: message.get_round_id(),
+            query_id: message.get_query_id(),
+        })
+    }
+}
+
+fn decode_vote(vote: u32) -> Result<u8, String> {
+    if vote > u32::from(std::u8::MAX) {
+        return Err(format!("vote {} out of range", vote));
+    }
+    Ok(vote as u8)
+}
+
 impl fmt::Display for SnowballMessage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "(message_type={}, seq={}, vote={})",
-            self.message_type, self.seq_num, self.vote
+            "(message_type={}, seq={}, vote={}, block_id={}, view={})",
+            self.message_type, self.seq_num, self.vote, hex::encode(&self.block_id), self.view
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response as sent by nodes which predate the protobuf encoding
+    const BASELINE_RESPONSE: &str = r#"{"message_type":"response","seq_num":7,"vote":1,"nonce":[1,2,3]}"#;
+
+    #[test]
+    fn decode_baseline_json() {
+        let message = SnowballMessage::from_bytes(BASELINE_RESPONSE.as_bytes()).unwrap();
+
+        assert_eq!(message.message_type, "response");
+        assert_eq!(message.seq_num, 7);
+        assert_eq!(message.vote, 1);
+        assert_eq!(message.nonce, vec![1, 2, 3]);
+        assert!(message.block_id.is_empty());
+        assert!(message.decisions.is_empty());
+        assert_eq!(message.view, 0);
+        assert!(message.tip.is_empty());
+    }
+
+    #[test]
+    fn round_trip_both_formats() {
+        let mut message = SnowballMessage::new();
+        message.message_type = String::from("sync_response");
+        message.seq_num = 12;
+        message.vote = 2;
+        message.block_id = vec![0xab; 4];
+        message.decisions = vec![SyncDecision { seq_num: 11, vote: 1, block_id: vec![0xcd; 4] }];
+        message.view = 3;
+        message.tip = vec![0xef; 4];
+        message.nonce = vec![9; 8];
+
+        for format in &[WireFormat::Json, WireFormat::Protobuf] {
+            let decoded = SnowballMessage::from_bytes(&message.to_bytes(*format)).unwrap();
+            assert_eq!(decoded, message, "{:?} round trip", format);
+        }
+    }
+
+    #[test]
+    fn peer_message_round_trip() {
+        let query = QueryId { round_id: 4, query_id: 5 };
+        let messages = vec![
+            PeerMessage::Request { seq_num: 1, query },
+            PeerMessage::Response { seq_num: 2, query, vote: 1, block_id: vec![0xab; 4], tip: vec![0xcd; 4] },
+            PeerMessage::Unavailable { seq_num: 3, query },
+            PeerMessage::FailoverRequest { seq_num: 4, view: 1 },
+            PeerMessage::FailoverResponse { seq_num: 5, view: 2 },
+            PeerMessage::SyncRequest { seq_num: 6 },
+            PeerMessage::SyncResponse {
+                seq_num: 7,
+                decisions: vec![SyncDecision { seq_num: 7, vote: 0, block_id: Vec::new() }],
+            },
+        ];
+
+        for message in messages {
+            let message_type = message.message_type();
+            let decoded = PeerMessage::decode(message_type, message.clone().into_payload()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn decode_type_mismatch() {
+        let payload = PeerMessage::SyncRequest { seq_num: 1 }.into_payload();
+        match PeerMessage::decode("request", payload) {
+            Err(MessageError::TypeMismatch { header, body }) => {
+                assert_eq!(header, "request");
+                assert_eq!(body, "sync_request");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_unknown_type() {
+        let mut payload = SnowballMessage::new();
+        payload.message_type = String::from("gossip");
+        match PeerMessage::decode("gossip", payload) {
+            Err(MessageError::UnknownType(message_type)) => assert_eq!(message_type, "gossip"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn consensus_payload_uses_hex() {
+        let payload = ConsensusPayload {
+            version: CONSENSUS_VERSION,
+            summary: vec![0x01, 0xff],
+            certificates: vec![FinalityCertificate {
+                seq_num: 3,
+                block_id: vec![0xab, 0xcd],
+                block_num: 3,
+                vote: 1,
+                confidence: 2,
+                round: 4,
+                response_buffer: vec![CertifiedVote { vote: 1, block_id: vec![0xab, 0xcd], count: 1 }],
+                responders: vec![vec![0x12, 0x34]],
+            }],
+        };
+
+        let encoded = payload.encode();
+        let json = String::from_utf8(encoded[CONSENSUS_PREFIX.len()..].to_vec()).unwrap();
+        assert!(json.contains(r#""summary":"01ff""#));
+        assert!(json.contains(r#""block_id":"abcd""#));
+        assert!(json.contains(r#""responders":["1234"]"#));
+
+        let decoded = ConsensusPayload::decode(&encoded).unwrap();
+        assert_eq!(decoded.summary, payload.summary);
+        assert_eq!(decoded.certificates[0].block_id, payload.certificates[0].block_id);
+        assert_eq!(decoded.certificates[0].response_buffer[0].block_id, vec![0xab, 0xcd]);
+        assert_eq!(decoded.certificates[0].responders, payload.certificates[0].responders);
+    }
+}
@@ -1,33 +1,204 @@
 use sawtooth_sdk::consensus::{engine::*, service::Service};
 
-use crate::config::{SnowballConfig};
-use crate::state::{SnowballState, SnowballPhase, SnowballDecisionState};
-use crate::message::{SnowballMessage, LogMessage};
-
-use std::collections::{HashSet, VecDeque};
+use crate::config::{SnowballConfig, SnowballVariant, EscalationPolicy, WireFormat};
+use crate::state::{SnowballState, SnowballInstance, SnowballPhase, SnowballDecisionState};
+use crate::message::{SnowballMessage, PeerMessage, MessageError, QueryId, SyncDecision, LogMessage, ConsensusPayload, FinalityCertificate, CertifiedVote, CONSENSUS_PREFIX, CONSENSUS_VERSION};
+use crate::timing::Timeout;
+use crate::sampler::{self, PeerSampler, SampleError};
+use crate::signing::{self, MessageSigner};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
 use std::thread::sleep;
 use std::time;
 
 use rand;
 use rand::distributions::{Distribution, Uniform};
 
-use safe_crypto::Nonce;
+use safe_crypto::{Nonce, SecretSignKey};
 
 use reqwest;
 
+/// Maximum number of decisions sent in a single sync response
+const MAX_SYNC_DECISIONS: usize = 100;
+
+/// Maximum number of queries about future instances held at the same time
+const MAX_DEFERRED_REQUESTS: usize = 100;
+
+/// Maximum number of certificates waiting to be embedded in a committed block;
+/// the oldest are dropped first
+const MAX_PENDING_CERTIFICATES: usize = 100;
+
+/// Maximum number of sequence numbers whose nonces are remembered per peer
+const MAX_NONCE_SEQ_NUMS: usize = 64;
+
+/// Maximum number of nonces remembered per peer and sequence number
+const MAX_NONCES_PER_SEQ_NUM: usize = 256;
+
+/// Maximum number of blocks walked from a tip preferred by a peer to reach a
+/// running instance
+const MAX_TIP_DEPTH: usize = 64;
+
 #[derive(Default)]
 struct LogGuard {
     not_ready_to_summarize: bool,
     not_ready_to_finalize: bool,
 }
 
+/// Progress of a catch-up exchange with a sample of peers
+#[derive(Default)]
+struct SyncProgress {
+    /// Peers which haven't answered the sync request yet
+    peers: HashSet<PeerId>,
+    /// Peers reporting each decision, indexed by sequence number
+    votes: HashMap<u64, HashMap<SnowballDecisionState, HashSet<PeerId>>>,
+    /// Deadline before a new sync request can be sent
+    timeout: Option<Timeout>,
+    /// Highest sequence number each peer sent a message about, beyond the
+    /// instances running here
+    ahead: HashMap<PeerId, u64>,
+}
+
+/// Query about an instance which didn't start yet, answered once it does
+struct DeferredRequest {
+    peer_id: PeerId,
+    seq_num: u64,
+    query: QueryId,
+    /// Expires when the query should be answered as unavailable
+    timeout: Timeout,
+}
+
+/// Nonces of the messages received from a peer, indexed by sequence number.
+/// The sequence numbers seen first are forgotten first; replaying messages
+/// about them is harmless, since their instances are over. Forgetting by age
+/// rather than by value keeps bogus far-off sequence numbers from pinning
+/// their entries while the current ones are evicted.
+#[derive(Default)]
+struct NonceCache {
+    nonces: HashMap<u64, VecDeque<Vec<u8>>>,
+    /// Sequence numbers in the order they were first seen
+    order: VecDeque<u64>,
+}
+
+impl NonceCache {
+    /// Remember `nonce`, returning false if it was already seen for `seq_num`
+    fn insert(&mut self, seq_num: u64, nonce: &[u8]) -> bool {
+        let order = &mut self.order;
+        let nonces = self.nonces.entry(seq_num).or_insert_with(|| {
+            order.push_back(seq_num);
+            VecDeque::new()
+        });
+        if nonces.iter().any(|seen| seen.as_slice() == nonce) {
+            return false;
+        }
+        if nonces.len() == MAX_NONCES_PER_SEQ_NUM {
+            nonces.pop_front();
+        }
+        nonces.push_back(nonce.to_vec());
+
+        while self.order.len() > MAX_NONCE_SEQ_NUMS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.nonces.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Snowball vote on the view of the next instance, started when its proposer
+/// doesn't produce a block in time. Colors are views, and the preference
+/// follows the Snowball update rule.
+struct FailoverVote {
+    /// Instance whose proposer didn't show up
+    seq_num: u64,
+    /// View currently preferred
+    preference: u64,
+    /// View which won the last round
+    last_majority: Option<u64>,
+    /// Consecutive rounds won by `last_majority`
+    confidence_counter: u64,
+    /// Rounds won by each view
+    decision_array: HashMap<u64, u64>,
+    /// Views reported in the current round
+    response_buffer: HashMap<u64, u64>,
+    /// Peers which haven't answered in the current round
+    waiting: HashSet<PeerId>,
+    /// Deadline of the current round
+    round_timeout: Timeout,
+}
+
+/// What decides the fate of held blocks, which are only looked at again once
+/// it changes
+#[derive(PartialEq)]
+struct QueueSnapshot {
+    chain_head: BlockId,
+    view: u64,
+    /// Sequence number and number of candidates of each running instance
+    instances: Vec<(u64, usize)>,
+}
+
+impl QueueSnapshot {
+    fn of(state: &SnowballState) -> Self {
+        QueueSnapshot {
+            chain_head: state.chain_head.clone(),
+            view: state.view,
+            instances: state.instances
+                .iter()
+                .map(|(seq_num, instance)| (*seq_num, instance.candidate_blocks.len()))
+                .collect(),
+        }
+    }
+}
+
 /// Contains the core logic of the Snowball node
 pub struct SnowballNode {
     /// Used for interactions with the validator
     service: Box<dyn Service>,
     rng: rand::rngs::ThreadRng,
+    /// Chooses the peers queried in each round
+    sampler: Box<dyn PeerSampler>,
     log_guard: LogGuard,
-    block_queue: VecDeque<Block>
+    block_queue: VecDeque<Block>,
+    /// Blocks which can't be voted on yet
+    held_blocks: VecDeque<Block>,
+    /// State of the chain when the held blocks were last looked at
+    held_snapshot: Option<QueueSnapshot>,
+    /// Held blocks whose consensus field is well formed
+    checked_blocks: HashSet<BlockId>,
+    /// Height of the parents of held blocks, as reported by the validator
+    parent_heights: HashMap<BlockId, u64>,
+    /// Sequence number and previous block id of the block being built, if any
+    initialized_block: Option<(u64, BlockId)>,
+    /// Sequence number of the last block published by this node
+    published_seq_num: u64,
+    sync: SyncProgress,
+    /// Expires when no block arrives from the proposer for too long
+    idle_timeout: Timeout,
+    failover: Option<FailoverVote>,
+    /// Listening instances whose round found too few peers to query
+    unsampled: HashSet<u64>,
+    /// Sequence number and view supported in the failover vote of a peer
+    supported_view: Option<(u64, u64)>,
+    /// Instances which reached the maximum number of rounds, indexed by
+    /// sequence number. Expires when the escalation policy applies, unless
+    /// peers report a decision first.
+    escalations: HashMap<u64, Timeout>,
+    deferred_requests: Vec<DeferredRequest>,
+    deferred_request_timeout: time::Duration,
+    /// Encoding of the messages sent to peers
+    wire_format: WireFormat,
+    /// Whether blocks published by nodes which predate the consensus payload
+    /// are accepted
+    legacy_blocks: bool,
+    /// Signs the messages sent to peers and verifies the ones received
+    signer: MessageSigner,
+    /// Nonces already received from each peer, to drop replayed messages
+    seen_nonces: HashMap<PeerId, NonceCache>,
+    /// Sequence numbers of the certificates embedded in each block published
+    /// by this node, which stop being pending once the block is committed
+    embedded_certificates: HashMap<BlockId, Vec<u64>>,
+    /// Parent and height of the blocks walked from tips beyond the window
+    tip_parents: HashMap<BlockId, (BlockId, u64)>,
 }
 
 impl SnowballNode {
@@ -43,21 +214,69 @@ impl SnowballNode {
             service: service,
             log_guard: LogGuard::default(),
             rng: rand::thread_rng(),
-            block_queue: VecDeque::new()
+            sampler: sampler::new_sampler(config.sampler),
+            block_queue: VecDeque::new(),
+            held_blocks: VecDeque::new(),
+            held_snapshot: None,
+            checked_blocks: HashSet::new(),
+            parent_heights: HashMap::new(),
+            initialized_block: None,
+            published_seq_num: 0,
+            sync: SyncProgress::default(),
+            idle_timeout: Timeout::new(config.idle_timeout),
+            failover: None,
+            unsampled: HashSet::new(),
+            supported_view: None,
+            escalations: HashMap::new(),
+            deferred_requests: Vec::new(),
+            deferred_request_timeout: config.deferred_request_timeout,
+            wire_format: config.wire_format,
+            legacy_blocks: config.legacy_blocks,
+            signer: MessageSigner::new(load_signing_key(config), config.public_keys.clone()),
+            seen_nonces: HashMap::new(),
+            embedded_certificates: HashMap::new(),
+            tip_parents: HashMap::new(),
         };
+        n.idle_timeout.start();
 
         state.chain_head = chain_head.block_id.clone();
+        state.chain_head_block_num = chain_head.block_num;
 
-        // TODO: GENERALIZE: ONLY FIRST NODE CAN PROPOSE
-        if state.order == 0 {
-            n.service.initialize_block(None).unwrap_or_else(|err| {
-                error!("Couldn't initialize block on startup due to error: {}", err)
-            });
-        }
+        n.try_initialize_block(state);
 
         n
     }
 
+    /// Start building the block for the next sequence number when this node
+    /// is its proposer and the window has room for another instance. The block
+    /// is built on top of the block preferred by the newest instance, so that
+    /// consecutive instances can run concurrently.
+    pub fn try_initialize_block(&mut self, state: &mut SnowballState) {
+        let seq_num = state.seq_num + 1;
+        if self.initialized_block.is_some() || self.published_seq_num >= seq_num {
+            return;
+        }
+
+        if state.instances.len() as u64 >= state.window {
+            return;
+        }
+
+        let previous_id = match state.get_tip() {
+            Some(previous_id) => previous_id,
+            None => return,
+        };
+
+        if !state.is_proposer(seq_num, &previous_id) {
+            return;
+        }
+
+        debug!("Initializing block for seq_num {} on top of {}", seq_num, hex::encode(&previous_id));
+        match self.service.initialize_block(Some(previous_id.clone())) {
+            Ok(_) => self.initialized_block = Some((seq_num, previous_id)),
+            Err(err) => error!("Couldn't initialize block due to error: {}", err),
+        }
+    }
+
     pub fn cancel_block(&mut self) {
         debug!("Canceling block");
         match self.service.cancel_block() {
@@ -68,7 +287,7 @@ impl SnowballNode {
         };
     }
 
-    fn finalize_block(&mut self) -> BlockId {
+    fn finalize_block(&mut self, state: &SnowballState) -> BlockId {
         debug!("Finalizing block");
         let mut summary = self.service.summarize_block();
         while let Err(Error::BlockNotReady) = summary {
@@ -83,7 +302,8 @@ impl SnowballNode {
         let summary = summary.expect("Failed to summarize block");
         debug!("Block has been summarized successfully");
 
-        let consensus: Vec<u8> = create_consensus(&summary);
+        let certificates = state.pending_certificates.values().cloned().collect();
+        let consensus: Vec<u8> = create_consensus(&summary, certificates);
         let mut block_id = self.service.finalize_block(consensus.clone());
         while let Err(Error::BlockNotReady) = block_id {
             if !self.log_guard.not_ready_to_finalize {
@@ -95,6 +315,7 @@ impl SnowballNode {
         }
         self.log_guard.not_ready_to_finalize = false;
         let block_id = block_id.expect("Failed to finalize block");
+        self.embedded_certificates.insert(block_id.clone(), state.pending_certificates.keys().cloned().collect());
         debug!(
             "Block has been finalized successfully: {:?}",
             hex::encode(&block_id)
@@ -105,23 +326,36 @@ impl SnowballNode {
 
     /// At a regular interval, try to finalize a block when the primary is ready
     pub fn try_publish(&mut self, state: &mut SnowballState) -> () {
-        if state.phase != SnowballPhase::Idle {
-            return;
-        }
+        let seq_num = match self.initialized_block {
+            Some((seq_num, _)) => seq_num,
+            None => return,
+        };
 
-        // TODO: GENERALIZE: ONLY FIRST NODE CAN PROPOSE
-        if state.order != 0 {
+        if seq_num != state.seq_num + 1 {
             return;
         }
 
-        self.finalize_block();
+        self.finalize_block(state);
+        self.initialized_block = None;
+        self.published_seq_num = seq_num;
     }
 
-    fn send_peer_notification(&mut self, peer_id: &PeerId, message: &str, seq_num: u64, state: &mut SnowballState) {
-        self.send_peer_message(peer_id, message, 0, seq_num, state);
+    /// Answer a query with the preference for `seq_num`. Without a preference
+    /// there is no honest vote to give yet, and the response carries an
+    /// abstention.
+    fn send_vote(&mut self, peer_id: &PeerId, seq_num: u64, query: QueryId, state: &mut SnowballState) {
+        let (vote, block_id) = match state.decision_map.get(&seq_num) {
+            Some(color) => color.to_vote(),
+            None => SnowballDecisionState::Undecided.to_vote(),
+        };
+        let tip = state.preferred_tip();
+        self.send_message(peer_id, PeerMessage::Response { seq_num, query, vote, block_id, tip }, state);
     }
 
-    fn send_peer_message(&mut self, peer_id: &PeerId, message: &str, v: u8, seq_num: u64, state: &mut SnowballState) {
+    fn send_message(&mut self, peer_id: &PeerId, peer_message: PeerMessage, state: &mut SnowballState) {
+        let message = peer_message.message_type();
+        let mut payload = peer_message.into_payload();
+
         // Byzantine test code for hung processes
         if state.byzantine_test.enabled && state.byzantine_test.hang_idx.contains(&state.order) {
             debug!("Byzantine process {} is hung and doesn't send the {} message", state.order, message);
@@ -129,11 +363,7 @@ impl SnowballNode {
         }
 
         let nonce = Nonce::new().into_bytes();
-        let mut payload = SnowballMessage::new();
-        payload.vote = v;
         payload.nonce = nonce.to_vec();
-        payload.seq_num = seq_num;
-        payload.message_type = String::from(message);
 
         // Byzantine test code for wrong decisions
         if state.byzantine_test.enabled && state.byzantine_test.wrong_decision_idx.contains(&state.order) {
@@ -154,6 +384,9 @@ impl SnowballNode {
             debug!("Byzantine process {} sleeping {} ms before sending a message", state.order, sleep_delay_millis);
         }
 
+        // Signed last, so that Byzantine processes sign what they send
+        payload.signature = self.signer.sign(&payload.signed_bytes());
+
         let mut reps = 1;
 
         // Byzantine test code for simulating delays
@@ -165,7 +398,7 @@ impl SnowballNode {
         for _ in 0..reps {
             debug!("Sending {} message to {:?}", message, hex::encode(&peer_id));
             self.service
-                .send_to(&peer_id, message, serde_json::to_string(&payload).unwrap().as_bytes().to_vec())
+                .send_to(&peer_id, message, payload.to_bytes(self.wire_format))
                 .expect("Failed to send message");
             state.set_message_sent();
         }
@@ -174,42 +407,229 @@ impl SnowballNode {
     // ---------- Methods for handling Updates from the Validator ----------
 
     pub fn handle_queue(&mut self, state: &mut SnowballState) {
-        let block: Block;
-        match self.block_queue.front() {
-            Some(x) => block = x.clone(),
-            None => return
+        // New blocks are always handled, held ones only when the chain moved
+        // since they were last looked at
+        let snapshot = QueueSnapshot::of(state);
+        let mut queue = VecDeque::new();
+        if self.held_snapshot.as_ref() != Some(&snapshot) {
+            queue.append(&mut self.held_blocks);
         }
-
-        if block.block_id.eq(&state.decision_block) {
+        queue.append(&mut self.block_queue);
+        self.held_snapshot = Some(snapshot);
+        if queue.is_empty() {
             return;
         }
 
-        debug!("Current queued blocks for process {}: {}", state.order, self.block_queue.len());
+        debug!("Current queued blocks for process {}: {}", state.order, queue.len());
 
-        // Only future blocks should be considered since committed blocks are final
-        
-        let chain_head_block = self.service
-            .get_chain_head()
-            .expect("Unable to get chain head.");
+        let mut waiting = VecDeque::new();
+        while let Some(block) = queue.pop_front() {
+            // Only future blocks should be considered since committed blocks are final
+            if block.block_id == state.chain_head {
+                continue;
+            }
+            if block.block_num <= state.chain_head_block_num {
+                warn!(
+                    "Received block {:?} / {:?} that is older than the current chain head: {:?}",
+                    block.block_num,
+                    hex::encode(&block.block_id),
+                    state.chain_head_block_num,
+                );
+                self.fail_block(block.block_id, block.block_num, state);
+                continue;
+            }
 
-        if block.block_num < chain_head_block.block_num {
-            self.service
-                .fail_block(block.block_id.clone())
-                .unwrap_or_else(|err| error!("Couldn't fail block due to error: {:?}", err));
-            warn!(
-                "Received block {:?} / {:?} that is older than the current sequence number: {:?}",
-                block.block_num,
-                hex::encode(&block.block_id),
-                state.seq_num,
-            );
+            // Blocks built on a failed or invalid parent can never be committed
+            if state.failed_blocks.contains_key(&block.previous_id)
+                || state.block_validation.get(&block.previous_id) == Some(&false)
+            {
+                warn!(
+                    "Received block {:?} / {:?} extending the failed block {:?}",
+                    block.block_num,
+                    hex::encode(&block.block_id),
+                    hex::encode(&block.previous_id),
+                );
+                self.fail_block(block.block_id, block.block_num, state);
+                continue;
+            }
+
+            // The consensus field never changes, only its parent's height
+            // may become known
+            let checked = if self.checked_blocks.contains(&block.block_id) {
+                Ok(())
+            } else {
+                check_consensus(&block, self.legacy_blocks)
+            };
+            if let Err(err) = checked.and_then(|()| check_block_num(&block, state)) {
+                warn!(
+                    "Received block {:?} / {:?} with a malformed consensus field from {}: {}",
+                    block.block_num,
+                    hex::encode(&block.block_id),
+                    hex::encode(&block.signer_id),
+                    err,
+                );
+                state.record_malformed_block(&block.signer_id);
+                self.fail_block(block.block_id, block.block_num, state);
+                continue;
+            }
+            self.checked_blocks.insert(block.block_id.clone());
+
+            // Blocks at the height of a running instance compete with its
+            // candidates, as long as they extend the same parent. Blocks on
+            // another branch wait until their parent is decided.
+            let running = state.instances
+                .values()
+                .find(|instance| instance.decision_block_num == block.block_num)
+                .map(|instance| (instance.seq_num, instance.previous_id().cloned()));
+            if let Some((seq_num, previous_id)) = running {
+                if previous_id.as_ref() == Some(&block.previous_id) {
+                    self.add_candidate(block, seq_num, state);
+                } else {
+                    waiting.push_back(block);
+                }
+                continue;
+            }
+
+            // New instances extend the chain head, or a candidate of the newest
+            // running instance
+            let extends_tip = match state.instances.values().next_back() {
+                Some(instance) => instance.candidate_blocks.contains(&block.previous_id),
+                None => block.previous_id == state.chain_head,
+            };
+            if !extends_tip {
+                if self.is_dead_branch(&block, state) {
+                    warn!(
+                        "Received block {:?} / {:?} extending an abandoned branch",
+                        block.block_num,
+                        hex::encode(&block.block_id),
+                    );
+                    self.fail_block(block.block_id, block.block_num, state);
+                } else {
+                    waiting.push_back(block);
+                }
+                continue;
+            }
+
+            // Only the scheduled proposer may open the next instance, as soon
+            // as the window has room for it
+            let seq_num = state.seq_num + 1;
+            self.adopt_supported_view(&block, seq_num, state);
+            if block.signer_id == state.get_proposer(seq_num, &block.previous_id) {
+                if (state.instances.len() as u64) < state.window {
+                    self.service
+                        .check_blocks(vec![block.block_id.clone()])
+                        .expect("Failed to check block");
+                    self.handle_block_new(block, state);
+                } else {
+                    waiting.push_back(block);
+                }
+                continue;
+            }
+
+            // Blocks of other members wait until the scheduled proposer opens
+            // the instance of their height, and then compete with its block
+            if state.is_member(&block.signer_id) {
+                waiting.push_back(block);
+                continue;
+            }
+
+            self.fail_foreign_block(&block, state);
+        }
+        self.held_blocks.append(&mut waiting);
+
+        // Forget what was learnt about blocks which left the queue
+        let held_ids: HashSet<BlockId> = self.held_blocks.iter().map(|block| block.block_id.clone()).collect();
+        let held_parents: HashSet<BlockId> = self.held_blocks.iter().map(|block| block.previous_id.clone()).collect();
+        self.checked_blocks.retain(|block_id| held_ids.contains(block_id));
+        self.parent_heights.retain(|block_id, _| held_parents.contains(block_id));
+    }
+
+    /// Whether the parent of `block` is on a branch which can't be committed
+    /// anymore, since a different block was committed at its height. Parents
+    /// unknown to the validator are still pending.
+    fn is_dead_branch(&mut self, block: &Block, state: &SnowballState) -> bool {
+        if block.previous_id == state.chain_head {
+            return false;
+        }
+
+        if let Some(parent_block_num) = self.parent_heights.get(&block.previous_id) {
+            return *parent_block_num <= state.chain_head_block_num;
+        }
+
+        match self.service.get_blocks(vec![block.previous_id.clone()]) {
+            Ok(blocks) => match blocks.get(&block.previous_id) {
+                Some(parent) => {
+                    self.parent_heights.insert(parent.block_id.clone(), parent.block_num);
+                    parent.block_num <= state.chain_head_block_num
+                }
+                None => false,
+            },
+            Err(err) => {
+                debug!("Parent of block {} is not available yet: {:?}", hex::encode(&block.block_id), err);
+                false
+            }
+        }
+    }
+
+    /// Fail a block, remembering it so that its descendants are failed too
+    fn fail_block(&mut self, block_id: BlockId, block_num: u64, state: &mut SnowballState) {
+        self.service
+            .fail_block(block_id.clone())
+            .unwrap_or_else(|err| error!("Couldn't fail block due to error: {:?}", err));
+        state.failed_blocks.insert(block_id, block_num);
+    }
+
+    /// Fail `block`, which was signed by a node outside the members setting
+    fn fail_foreign_block(&mut self, block: &Block, state: &mut SnowballState) {
+        warn!(
+            "Received block {:?} / {:?} from {:?}, which is not a member",
+            block.block_num,
+            hex::encode(&block.block_id),
+            hex::encode(&block.signer_id),
+        );
+        self.fail_block(block.block_id.clone(), block.block_num, state);
+    }
+
+    /// Add a block competing with the ones already voted in instance `seq_num`
+    fn add_candidate(&mut self, block: Block, seq_num: u64, state: &mut SnowballState) {
+        let (known, decided) = match state.instances.get(&seq_num) {
+            Some(instance) => (
+                instance.candidate_blocks.contains(&block.block_id),
+                instance.phase == SnowballPhase::Finishing,
+            ),
+            None => return,
+        };
+        if known {
             return;
         }
 
+        // Any member may compete, the vote picks the winner
+        if !state.is_member(&block.signer_id) {
+            self.fail_foreign_block(&block, state);
+            return;
+        }
+
+        // A decided instance can't be reopened, late competitors lose
+        if decided {
+            warn!("Received block {} for the already decided seq_num {}", hex::encode(&block.block_id), seq_num);
+            self.fail_block(block.block_id, block.block_num, state);
+            return;
+        }
+
+        info!(
+            "Process {} adding competing block {} for seq_num {}",
+            state.order,
+            hex::encode(&block.block_id),
+            seq_num
+        );
+
         self.service
             .check_blocks(vec![block.block_id.clone()])
             .expect("Failed to check block");
 
-        self.handle_block_new(block.block_id, state);
+        let instance = state.instances.get_mut(&seq_num).unwrap();
+        instance.candidate_parents.insert(block.block_id.clone(), block.previous_id);
+        instance.candidate_blocks.push(block.block_id);
     }
 
     pub fn on_block_new(&mut self, block: Block, state: &mut SnowballState) -> bool {
@@ -228,28 +648,163 @@ impl SnowballNode {
         true
     }
 
-    pub fn prepare_and_forward_peer_requests(&mut self, sample: HashSet<usize>, state: &mut SnowballState) {
-        debug!("Preparing new peer notifications.");
-        state.response_buffer = [0, 0];
+    pub fn prepare_and_forward_peer_requests(&mut self, sample: HashSet<usize>, seq_num: u64, state: &mut SnowballState) {
+        debug!("Preparing new peer notifications for seq_num {}.", seq_num);
+        if let Some(instance) = state.instances.get_mut(&seq_num) {
+            instance.response_buffer.clear();
+            instance.responders.clear();
+            instance.tips.clear();
+            instance.abstentions = 0;
+            // Answers to the previous round are late from now on
+            instance.round_id += 1;
+            instance.abandon_queries();
+            instance.queried_peers.clear();
+        }
         for index in sample {
             let peer_id = state.member_ids.get(index).cloned().unwrap();
-            self.send_peer_notification(&peer_id, "request", state.seq_num, state);
-            state.add_to_waiting_set(peer_id.clone());
+            if let Some(query) = state.add_to_waiting_set(seq_num, peer_id.clone()) {
+                self.send_message(&peer_id, PeerMessage::Request { seq_num, query }, state);
+            }
         }
     }
 
+    /// Query a new peer in place of one that won't answer for instance `seq_num`
+    fn request_replacement_peer(&mut self, seq_num: u64, state: &mut SnowballState) {
+        // I find another node to send a request to, which wasn't asked in the
+        // current round: its answer would count twice, and a peer which timed
+        // out is unlikely to answer now
+        let queried: HashSet<usize> = match state.instances.get(&seq_num) {
+            Some(instance) => state.member_ids
+                .iter()
+                .enumerate()
+                .filter(|(_, peer_id)| instance.queried_peers.contains(*peer_id))
+                .map(|(index, _)| index)
+                .collect(),
+            None => return,
+        };
+
+        let peer_id = match self.sampler.sample(state, &queried, 1) {
+            Ok(sample) => state.member_ids[*sample.iter().next().unwrap()].clone(),
+            Err(err) => {
+                warn!("Process {} can't replace a peer for seq_num {}: {}", state.order, seq_num, err);
+
+                // With every peer asked and no answer outstanding, the round
+                // ends with the votes collected so far instead of stalling
+                let exhausted = state.instances
+                    .get(&seq_num)
+                    .map_or(false, |instance| {
+                        instance.phase == SnowballPhase::Listening && instance.waiting_response_map.is_empty()
+                    });
+                if exhausted {
+                    self.on_values_ready(seq_num, state);
+                }
+                return;
+            }
+        };
+        let query = match state.add_to_waiting_set(seq_num, peer_id.clone()) {
+            Some(query) => query,
+            None => return,
+        };
+
+        info!("Sending additional peer notifications to {:?}.", hex::encode(&peer_id));
+        self.send_message(&peer_id, PeerMessage::Request { seq_num, query }, state);
+    }
+
     pub fn on_block_valid(&mut self, block_id: BlockId, state: &mut SnowballState) -> bool {
         info!("Got BlockValid: {}", hex::encode(&block_id));
 
+        state.block_validation.insert(block_id, true);
+        self.handle_pending_validation(state);
+
         true
     }
 
-    pub fn on_block_invalid(&mut self, block_id: BlockId) -> bool {
+    pub fn on_block_invalid(&mut self, block_id: BlockId, state: &mut SnowballState) -> bool {
         info!("Got BlockInvalid: {}", hex::encode(&block_id));
 
+        state.block_validation.insert(block_id, false);
+        self.handle_pending_validation(state);
+
         true
     }
 
+    /// Answer the deferred queries whose instance has a preference by now.
+    /// When their deadline expires, queries are answered with the current
+    /// preference, which may be an abstention, or as unavailable if the
+    /// instance didn't start.
+    pub fn handle_deferred_requests(&mut self, state: &mut SnowballState) {
+        if self.deferred_requests.is_empty() {
+            return;
+        }
+
+        let mut pending = Vec::new();
+        for mut request in std::mem::replace(&mut self.deferred_requests, Vec::new()) {
+            let preferred = match state.decision_map.get(&request.seq_num) {
+                Some(SnowballDecisionState::Undecided) | None => false,
+                Some(_) => request.seq_num <= state.seq_num,
+            };
+
+            if preferred || (request.timeout.check_expired() && request.seq_num <= state.seq_num) {
+                debug!("Process {} answers the deferred request of {} for seq_num {}", state.order, hex::encode(&request.peer_id), request.seq_num);
+                self.send_vote(&request.peer_id, request.seq_num, request.query, state);
+            } else if request.timeout.check_expired() {
+                let unavailable = PeerMessage::Unavailable {
+                    seq_num: request.seq_num,
+                    query: request.query,
+                };
+                self.send_message(&request.peer_id, unavailable, state);
+            } else {
+                pending.push(request);
+            }
+        }
+        self.deferred_requests = pending;
+    }
+
+    /// Choose the initial preference of the instances waiting for the
+    /// validator's verdicts, and start querying peers. The first valid
+    /// candidate is preferred, `KO` is preferred when all of them are invalid,
+    /// and the node abstains if no verdict arrives before the deadline.
+    pub fn handle_pending_validation(&mut self, state: &mut SnowballState) {
+        let validating: Vec<u64> = state.instances
+            .values()
+            .filter(|instance| instance.phase == SnowballPhase::Validating)
+            .map(|instance| instance.seq_num)
+            .collect();
+
+        for seq_num in validating {
+            let instance = state.instances.get_mut(&seq_num).unwrap();
+            let block_validation = &state.block_validation;
+            let valid_block = instance.candidate_blocks
+                .iter()
+                .find(|block_id| block_validation.get(*block_id) == Some(&true))
+                .cloned();
+            let all_invalid = instance.candidate_blocks
+                .iter()
+                .all(|block_id| block_validation.get(block_id) == Some(&false));
+
+            let my_decision = match valid_block {
+                Some(block_id) => SnowballDecisionState::OK(block_id),
+                None if all_invalid => SnowballDecisionState::KO,
+                None if instance.validation_timeout.check_expired() => {
+                    warn!("Process {} received no verdict for seq_num {} in time, abstaining", state.order, seq_num);
+                    SnowballDecisionState::Undecided
+                }
+                None => continue,
+            };
+
+            info!("Process {} starting seq_num {} with preference {}", state.order, seq_num, my_decision);
+            instance.validation_timeout.stop();
+            instance.last_color = my_decision.clone();
+            instance.switch_phase();
+            let k = instance.params.k;
+            state.decision_map.insert(seq_num, my_decision);
+
+            self.start_round(seq_num, k as usize, state);
+        }
+
+        self.try_initialize_block(state);
+    }
+
     pub fn on_block_commit(&mut self, block_id: BlockId, state: &mut SnowballState) -> bool {
         info!("Got BlockCommit: {}", hex::encode(&block_id));
 
@@ -284,192 +839,1038 @@ impl SnowballNode {
         true
     }
 
-    pub fn on_peer_message(&mut self, message: &str, sender_id: &PeerId, payload: SnowballMessage, state: &mut SnowballState) -> bool {
-        debug!("Got PeerMessage with message {}", message);
+    pub fn on_peer_message(&mut self, message_type: &str, sender_id: &PeerId, payload: SnowballMessage, state: &mut SnowballState) -> bool {
+        debug!("Got PeerMessage with message {}", message_type);
+
+        if self.signer.enforced() {
+            if let Err(err) = self.signer.verify(sender_id, &payload.signed_bytes(), &payload.signature) {
+                warn!("Process {} dropped {} message from {:?}: {}", state.order, message_type, hex::encode(&sender_id), err);
+                state.record_invalid_signature(sender_id);
+                return false;
+            }
+        }
+
+        // A message seen before is a duplicate or a replay, and was already handled
+        let fresh = self.seen_nonces
+            .entry(sender_id.clone())
+            .or_default()
+            .insert(payload.seq_num, &payload.nonce);
+        if !fresh {
+            warn!("Process {} dropped replayed {} message from {:?} for seq_num {}", state.order, message_type, hex::encode(&sender_id), payload.seq_num);
+            state.measurements.replayed_messages += 1;
+            return false;
+        }
+
+        let message = match PeerMessage::decode(message_type, payload) {
+            Ok(message) => message,
+            Err(err @ MessageError::UnknownType(_)) => {
+                state.measurements.unknown_messages += 1;
+                warn!("Process {} dropped message from {:?}: {}", state.order, hex::encode(&sender_id), err);
+                return false;
+            }
+            Err(err) => {
+                warn!("Process {} dropped malformed message from {:?}: {}", state.order, hex::encode(&sender_id), err);
+                return false;
+            }
+        };
 
-        if state.seq_num != payload.seq_num {
-            warn!("Process {} received message for seq_num {} when it was on seq_num {}", state.order, payload.seq_num, state.seq_num);
+        match &message {
+            PeerMessage::Request { seq_num, .. } | PeerMessage::Response { seq_num, .. } if !state.instances.contains_key(seq_num) => {
+                warn!("Process {} received message for seq_num {} when it was on seq_num {}", state.order, seq_num, state.seq_num);
+
+                // Enough peers this far ahead means some decisions were
+                // missed; a single peer could make up the sequence number
+                let horizon = state.seq_num + state.window;
+                if *seq_num > horizon {
+                    let ahead = self.sync.ahead.entry(sender_id.clone()).or_insert(0);
+                    *ahead = (*ahead).max(*seq_num);
+                    let peers_ahead = self.sync.ahead.values().filter(|ahead| **ahead > horizon).count();
+                    if peers_ahead as u64 >= state.parameters().alfa_confidence {
+                        self.request_sync(state);
+                    }
+                }
+            }
+            _ => {}
         }
 
         match message {
-            "request" => {
-                if payload.seq_num > state.seq_num {
-                    self.send_peer_notification(sender_id, "unavailable", payload.seq_num, state);
+            PeerMessage::Request { seq_num, query } => {
+                if seq_num > state.seq_num {
+                    // The block of a nearby instance is likely on its way, the
+                    // query is answered when the instance starts
+                    let nearby = seq_num <= state.seq_num + state.window;
+                    if nearby && self.deferred_requests.len() < MAX_DEFERRED_REQUESTS {
+                        debug!("Process {} defers the request of {} for seq_num {}", state.order, hex::encode(&sender_id), seq_num);
+                        let mut timeout = Timeout::new(self.deferred_request_timeout);
+                        timeout.start();
+                        self.deferred_requests.push(DeferredRequest {
+                            peer_id: sender_id.clone(),
+                            seq_num,
+                            query,
+                            timeout,
+                        });
+                    } else {
+                        self.send_message(sender_id, PeerMessage::Unavailable { seq_num, query }, state);
+                    }
                     return false;
                 }
 
-                let seq_value = state.decision_map.get(&payload.seq_num);
-                if seq_value == None {
-                    error!("Process {} unable to find seq_num in map for seq_num {}. Doing nothing.", state.order, payload.seq_num);
+                if state.decision_map.get(&seq_num) == None {
+                    error!("Process {} unable to find seq_num in map for seq_num {}. Doing nothing.", state.order, seq_num);
+                    state.record_spurious_seq_num(sender_id);
                     return false;
                 }
 
-                let current_value: u8 = if *seq_value.unwrap() == SnowballDecisionState::OK { 1 } else { 0 };
-                self.send_peer_message(sender_id, "response", current_value, state.seq_num, state);
+                debug!("Process {} received a request from {} for seq_num {}", state.order, hex::encode(&sender_id), seq_num);
+                self.send_vote(sender_id, seq_num, query, state);
             }
-            "response" => {
-                if state.phase != SnowballPhase::Listening {
-                    warn!("Process {} received a response message when it was not listening. Current state: {}", state.order, state);
-                    return false;
-                }
-                if !state.waiting_response_map.contains_key(sender_id) {
-                    warn!("Process {} received unwaited message from {:?}", state.order, sender_id);
-                    return false;
+            PeerMessage::Response { seq_num, query, vote, block_id, tip } => {
+                match state.instances.get(&seq_num) {
+                    Some(instance) if instance.phase == SnowballPhase::Listening => {}
+                    _ => {
+                        warn!("Process {} received a response message for seq_num {} when it was not listening. Current state: {}", state.order, seq_num, state);
+                        // Nobody was asked about instances which didn't start yet
+                        if seq_num > state.seq_num {
+                            state.record_spurious_seq_num(sender_id);
+                        }
+                        // Still counted when it answers a query abandoned at the decision
+                        state.take_response(seq_num, sender_id, query);
+                        return false;
+                    }
                 }
 
                 // a message arrived from a node I was waiting for a response, I
                 // remove it from the waiting response set
-                state.waiting_response_map.remove(sender_id);
+                let latency = match state.take_response(seq_num, sender_id, query) {
+                    Some(latency) => latency,
+                    None => {
+                        warn!("Process {} received unwaited response from {:?} for seq_num {} ({})", state.order, hex::encode(&sender_id), seq_num, query);
+                        return false;
+                    }
+                };
+                state.record_response(sender_id, latency);
+
+                let color = match SnowballDecisionState::from_vote(vote, block_id) {
+                    Some(color) => color,
+                    None => {
+                        error!("Process {} received invalid vote ({}) from node {:?}", state.order, vote, hex::encode(&sender_id));
+                        state.record_invalid_vote(sender_id);
+                        return false;
+                    }
+                };
+                let instance = state.instances.get_mut(&seq_num).unwrap();
+
+                // Abstentions don't count as votes, another peer is asked instead.
+                // The abstainer stays in `queried_peers`, so it isn't asked
+                // again before the round ends.
+                if color == SnowballDecisionState::Undecided {
+                    debug!("Process {} abstained for seq_num {}", hex::encode(&sender_id), seq_num);
+                    instance.abstentions += 1;
+                    instance.total_abstentions += 1;
+                    self.request_replacement_peer(seq_num, state);
+                    return true;
+                }
 
-                if payload.vote != 0 && payload.vote != 1 {
-                    error!("Process {} received invalid vote ({}) from node {:?}", state.order, payload.vote, hex::encode(&sender_id));
-                    return false;
+                *instance.response_buffer.entry(color).or_insert(0) += 1;
+                instance.responders.push(sender_id.clone());
+                instance.tips.push(tip);
+                if instance.response_buffer.values().sum::<u64>() == instance.params.k {
+                    info!("Process {} received all the messages for seq_num {}: {:?}", state.order, seq_num, instance.response_buffer);
+                    self.on_values_ready(seq_num, state);
+                }
+            }
+            PeerMessage::Unavailable { seq_num, query } => {
+                match state.instances.get(&seq_num) {
+                    Some(instance) if instance.phase == SnowballPhase::Listening => {}
+                    _ => {
+                        warn!("Process {} received a `unexpected` message for seq_num {} when it was not listening. Current state: {}", state.order, seq_num, state);
+                        return false;
+                    }
                 }
-                state.response_buffer[payload.vote as usize] += 1;
-                if state.response_buffer[0] + state.response_buffer[1] == state.k {
-                    info!("Process {} received all the messages for this round: {:?}", state.order, state.response_buffer);
-                    self.on_values_ready(state);
+
+                // a message arrived from a node I was waiting for a response, I
+                // remove it from the waiting response set
+                let latency = match state.take_response(seq_num, sender_id, query) {
+                    Some(latency) => latency,
+                    None => {
+                        warn!("Process {} received unwaited message from {:?} for seq_num {} ({})", state.order, hex::encode(&sender_id), seq_num, query);
+                        return false;
+                    }
+                };
+                state.record_response(sender_id, latency);
+
+                self.request_replacement_peer(seq_num, state);
+            }
+            PeerMessage::FailoverRequest { seq_num, view } => {
+                // Report the view this node prefers for the instance. A node
+                // which didn't get the block of the instance either supports
+                // the next view, even before its own idle timeout expires.
+                let view = match &self.failover {
+                    Some(failover) if failover.seq_num == seq_num => failover.preference,
+                    _ if seq_num == state.seq_num + 1 && view == state.view + 1 => {
+                        self.supported_view = Some((seq_num, view));
+                        // Held blocks may come from the new proposer
+                        self.held_snapshot = None;
+                        view
+                    }
+                    _ => state.view,
+                };
+                self.send_message(sender_id, PeerMessage::FailoverResponse { seq_num, view }, state);
+            }
+            PeerMessage::FailoverResponse { seq_num, view } => {
+                let ready = match self.failover.as_mut() {
+                    Some(failover) if failover.seq_num == seq_num && failover.waiting.contains(sender_id) => {
+                        failover.waiting.remove(sender_id);
+                        *failover.response_buffer.entry(view).or_insert(0) += 1;
+                        failover.waiting.is_empty()
+                    }
+                    _ => {
+                        warn!("Process {} received unwaited failover response from {:?}", state.order, hex::encode(&sender_id));
+                        return false;
+                    }
+                };
+                if ready {
+                    self.on_failover_round_end(state);
                 }
             }
-            "unavailable" => {
-                if state.phase != SnowballPhase::Listening {
-                    warn!("Process {} received a `unexpected` message when it was not listening. Current state: {}", state.order, state);
-                    return false;
+            PeerMessage::SyncRequest { seq_num: from } => {
+                // Only contiguous decisions are shared, starting from the
+                // requested sequence number
+                let mut decisions = Vec::new();
+                let mut seq_num = from;
+                while seq_num <= state.seq_num && !state.instances.contains_key(&seq_num) && decisions.len() < MAX_SYNC_DECISIONS {
+                    match state.decision_map.get(&seq_num) {
+                        Some(SnowballDecisionState::Undecided) | None => break,
+                        Some(color) => {
+                            let (vote, block_id) = color.to_vote();
+                            decisions.push(SyncDecision { seq_num, vote, block_id });
+                        }
+                    }
+                    seq_num += 1;
                 }
 
-                if !state.waiting_response_map.contains_key(sender_id) {
-                    warn!("Process {} received unwaited message from {:?}", state.order, hex::encode(&sender_id));
+                debug!("Process {} sharing {} decisions from seq_num {}", state.order, decisions.len(), from);
+                self.send_message(sender_id, PeerMessage::SyncResponse { seq_num: from, decisions }, state);
+            }
+            PeerMessage::SyncResponse { decisions, .. } => {
+                if !self.sync.peers.remove(sender_id) {
+                    warn!("Process {} received unwaited sync response from {:?}", state.order, hex::encode(&sender_id));
                     return false;
                 }
 
-                // a message arrived from a node I was waiting for a response, I
-                // remove it from the waiting response set
-                state.waiting_response_map.remove(sender_id);
-
-                // I find another node to send a request to, which is not in my
-                // current waiting response set
-                let mut peer_id = Vec::new();
-                let missing_responses_len = state.waiting_response_map.len();
-                while state.waiting_response_map.len() < missing_responses_len + 1 {
-                    let extra_node_set = self.select_node_sample(state, 1);
-                    for extra_node_index in extra_node_set {
-                        peer_id = state.member_ids[extra_node_index].clone();
-                        state.add_to_waiting_set(peer_id.clone());
+                for decision in decisions {
+                    // Abstentions are not decisions
+                    let color = SnowballDecisionState::from_vote(decision.vote, decision.block_id)
+                        .filter(|color| *color != SnowballDecisionState::Undecided);
+                    if let Some(color) = color {
+                        self.sync.votes
+                            .entry(decision.seq_num)
+                            .or_insert_with(HashMap::new)
+                            .entry(color)
+                            .or_insert_with(HashSet::new)
+                            .insert(sender_id.clone());
                     }
                 }
-                
-                info!("Sending additional peer notifications to {:?}.", hex::encode(&peer_id));
-                self.send_peer_notification(&peer_id, "request", state.seq_num, state);
+
+                self.apply_synced_decisions(state);
             }
-            _ => { }
         }
 
         true
     }
 
-    pub fn usize_to_decision_state(&mut self, i: usize) -> SnowballDecisionState {
-        match i {
-            0 => SnowballDecisionState::KO,
-            1 => SnowballDecisionState::OK,
-            _ => { panic!("Invalid value: {}", i); }
-        }       
+    pub fn on_values_ready(&mut self, seq_num: u64, state: &mut SnowballState) {
+        info!("Processing on values ready for process {} and seq_num {}", state.order, seq_num);
+
+        // Colors which are not candidates of this instance can't be adopted,
+        // and with alfa above k/2 at most one color gathers a majority. The
+        // majority changes the preference, and only builds confidence when it
+        // also reaches alfa_confidence.
+        let params = state.instances[&seq_num].params;
+        let (majority, confident) = {
+            let instance = &state.instances[&seq_num];
+            debug!(
+                "Response buffer={:?}, alfa_preference={}, alfa_confidence={}",
+                instance.response_buffer, params.alfa_preference, params.alfa_confidence
+            );
+            let majority = instance.response_buffer
+                .iter()
+                .filter(|(color, count)| **count >= params.alfa_preference && state.is_candidate(seq_num, color))
+                .max_by_key(|(_, count)| **count);
+            match majority {
+                Some((color, count)) => (Some(color.clone()), *count >= params.alfa_confidence),
+                None => (None, false),
+            }
+        };
+
+        let round = {
+            let instance = state.instances.get_mut(&seq_num).unwrap();
+            instance.round += 1;
+            if instance.abstentions > 0 {
+                info!("Round {} of seq_num {} had {} abstentions", instance.round, seq_num, instance.abstentions);
+            }
+            instance.round
+        };
+        let decided = self.apply_round(seq_num, majority, confident, state);
+        let credited = self.credit_ancestors(seq_num, state);
+
+        if decided {
+            state.instances.get_mut(&seq_num).unwrap().switch_phase();
+            self.commit_decided_instances(state);
+            return;
+        }
+
+        if credited {
+            self.commit_decided_instances(state);
+        }
+
+        if state.max_rounds > 0 && round >= state.max_rounds {
+            self.escalate(seq_num, state);
+            return;
+        }
+
+        self.start_round(seq_num, params.k as usize, state);
     }
 
-    pub fn decision_state_to_u8(&mut self, decision: SnowballDecisionState) -> u8 {
-        match decision {
-            SnowballDecisionState::OK => 1,
-            SnowballDecisionState::KO => 0,
-            SnowballDecisionState::Undecided => { panic!("Invalid conversion: {}", decision); }
+    /// Query a sample of `k` peers for instance `seq_num`. When not enough
+    /// peers are available, the round is retried on the next pass of the
+    /// engine loop.
+    fn start_round(&mut self, seq_num: u64, k: usize, state: &mut SnowballState) {
+        match self.select_node_sample(state, k) {
+            Ok(sample) => {
+                self.unsampled.remove(&seq_num);
+                self.prepare_and_forward_peer_requests(sample, seq_num, state);
+            }
+            Err(err) => {
+                if self.unsampled.insert(seq_num) {
+                    error!("Process {} can't query peers for seq_num {}, retrying: {}", state.order, seq_num, err);
+                }
+            }
         }
     }
 
-    pub fn on_values_ready(&mut self, state: &mut SnowballState) {
-        info!("Processing on values ready for process {}", state.order);
-        let mut majority = false;
-        for i in 0..=1 {
-            let col_i = self.usize_to_decision_state(i);
-            debug!("Response buffer={:?} for {}, alfa={}", state.response_buffer, i, state.alfa);
-            if state.response_buffer[i] >= state.alfa {
-                majority = true;
-                state.decision_array[i] += 1;
-                let current_color = state.decision_map.get(&state.seq_num).unwrap();
-                if state.decision_array[i] > state.decision_array[self.decision_state_to_u8(*current_color) as usize] {
-                    state.decision_map.insert(state.seq_num, col_i);
+    /// Start again the rounds which found too few peers to query
+    pub fn retry_unsampled_rounds(&mut self, state: &mut SnowballState) {
+        let unsampled: Vec<u64> = self.unsampled.iter().cloned().collect();
+        for seq_num in unsampled {
+            let k = match state.instances.get(&seq_num) {
+                Some(instance) if instance.phase == SnowballPhase::Listening && instance.waiting_response_map.is_empty() => {
+                    instance.params.k
                 }
-                if col_i != state.last_color {
-                    state.last_color = col_i;
-                    state.confidence_counter = 1;
+                _ => {
+                    self.unsampled.remove(&seq_num);
+                    continue;
                 }
-                else {
-                    state.confidence_counter += 1;
+            };
+            self.start_round(seq_num, k as usize, state);
+        }
+    }
+
+    /// Update the preference and confidence of instance `seq_num` with the
+    /// outcome of a round, following the configured variant. Returns whether
+    /// the instance is decided.
+    fn apply_round(
+        &mut self,
+        seq_num: u64,
+        majority: Option<SnowballDecisionState>,
+        confident: bool,
+        state: &mut SnowballState,
+    ) -> bool {
+        let current_color = state.decision_map.get(&seq_num).cloned().unwrap();
+        let instance = state.instances.get_mut(&seq_num).unwrap();
+        let beta = instance.params.beta;
+        let (preference, decided) = match state.variant {
+            // Slush adopts every majority, and stops after a fixed number of
+            // rounds without tracking any confidence
+            SnowballVariant::Slush => {
+                (majority.unwrap_or(current_color), instance.round >= beta)
+            }
+            // Snowflake only counts the consecutive successes of its preference
+            SnowballVariant::Snowflake => match majority {
+                Some(col_i) => {
+                    if !confident {
+                        instance.confidence_counter = 0;
+                    }
+                    else if col_i != current_color {
+                        instance.confidence_counter = 1;
+                    }
+                    else {
+                        instance.confidence_counter += 1;
+                    }
+                    (col_i, instance.confidence_counter >= beta)
+                }
+                None => {
+                    instance.confidence_counter = 0;
+                    (current_color, false)
+                }
+            },
+            // Snowball prefers the color with the most successful rounds
+            SnowballVariant::Snowball => match majority {
+                Some(col_i) => {
+                    let col_i_count = {
+                        let count = instance.decision_array.entry(col_i.clone()).or_insert(0);
+                        *count += 1;
+                        *count
+                    };
+                    let current_count = instance.decision_array.get(&current_color).cloned().unwrap_or(0);
+                    let preference = if col_i_count > current_count { col_i.clone() } else { current_color };
+                    if !confident {
+                        instance.confidence_counter = 0;
+                    }
+                    else if col_i != instance.last_color {
+                        instance.last_color = col_i;
+                        instance.confidence_counter = 1;
+                    }
+                    else {
+                        instance.confidence_counter += 1;
+                    }
+                    (preference, instance.confidence_counter >= beta)
+                }
+                None => {
+                    instance.confidence_counter = 0;
+                    (current_color, false)
+                }
+            },
+        };
+        state.decision_map.insert(seq_num, preference);
+
+        decided
+    }
+
+    /// A vote for a block is a vote for its ancestors as well: the tips
+    /// preferred by the peers answering a round of instance `seq_num` are
+    /// credited as successful rounds to the other undecided instances on
+    /// their chain. Returns whether any of those got decided.
+    fn credit_ancestors(&mut self, seq_num: u64, state: &mut SnowballState) -> bool {
+        let chain_head_block_num = state.chain_head_block_num;
+        self.tip_parents.retain(|_, (_, block_num)| *block_num > chain_head_block_num);
+
+        let mut resolved: HashMap<BlockId, Option<BlockId>> = HashMap::new();
+        let mut votes: HashMap<u64, HashMap<BlockId, u64>> = HashMap::new();
+        for tip in state.instances[&seq_num].tips.clone() {
+            if !resolved.contains_key(&tip) {
+                let ancestor = self.running_ancestor(&tip, state);
+                resolved.insert(tip.clone(), ancestor);
+            }
+            let ancestor = match &resolved[&tip] {
+                Some(ancestor) => ancestor.clone(),
+                None => continue,
+            };
+            for (ancestor_seq_num, block_id) in state.ancestors(&ancestor) {
+                if ancestor_seq_num != seq_num {
+                    *votes.entry(ancestor_seq_num).or_default().entry(block_id).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut decided_any = false;
+        for (ancestor_seq_num, counts) in votes {
+            let params = match state.instances.get(&ancestor_seq_num) {
+                Some(instance) if instance.phase == SnowballPhase::Listening => instance.params,
+                _ => continue,
+            };
+            let majority = counts
+                .into_iter()
+                .map(|(block_id, count)| (SnowballDecisionState::OK(block_id), count))
+                .filter(|(color, count)| *count >= params.alfa_preference && state.is_candidate(ancestor_seq_num, color))
+                .max_by_key(|(_, count)| *count);
+            let (color, count) = match majority {
+                Some(majority) => majority,
+                None => continue,
+            };
+
+            debug!(
+                "Process {} credits {} votes for {} to seq_num {} from a round of seq_num {}",
+                state.order, count, color, ancestor_seq_num, seq_num
+            );
+            if self.apply_round(ancestor_seq_num, Some(color), count >= params.alfa_confidence, state) {
+                // Queries still in flight aren't needed anymore
+                let instance = state.instances.get_mut(&ancestor_seq_num).unwrap();
+                instance.abandon_queries();
+                instance.switch_phase();
+                decided_any = true;
+            }
+        }
+
+        decided_any
+    }
+
+    /// Nearest block on the chain ending with `tip` which is a candidate of a
+    /// running instance. Tips beyond the window are walked up through the
+    /// validator, until a running instance or the chain head is reached.
+    fn running_ancestor(&mut self, tip: &BlockId, state: &SnowballState) -> Option<BlockId> {
+        let mut current = tip.clone();
+        for _ in 0..MAX_TIP_DEPTH {
+            if state.instances.values().any(|instance| instance.candidate_blocks.contains(&current)) {
+                return Some(current);
+            }
+            if current == state.chain_head {
+                return None;
+            }
+
+            let (previous_id, block_num) = match self.tip_parents.get(&current) {
+                Some(parent) => parent.clone(),
+                None => {
+                    let block = match self.service.get_blocks(vec![current.clone()]) {
+                        Ok(mut blocks) => blocks.remove(&current)?,
+                        Err(err) => {
+                            debug!("Block {} preferred by a peer is not available: {:?}", hex::encode(&current), err);
+                            return None;
+                        }
+                    };
+                    self.tip_parents.insert(current.clone(), (block.previous_id.clone(), block.block_num));
+                    (block.previous_id, block.block_num)
+                }
+            };
+            if block_num <= state.chain_head_block_num {
+                return None;
+            }
+            current = previous_id;
+        }
+        None
+    }
+
+    /// Escalate an instance which reached the maximum number of rounds
+    /// without deciding. Restarting the vote involves every peer anyway;
+    /// deciding on its own, a node could fail a block its peers commit, so it
+    /// first asks them for their decisions and only applies the policy when
+    /// none arrives in time.
+    fn escalate(&mut self, seq_num: u64, state: &mut SnowballState) {
+        warn!(
+            "Process {} reached {} rounds for seq_num {}, escalating with policy {}",
+            state.order, state.max_rounds, seq_num, state.escalation
+        );
+
+        match state.escalation {
+            EscalationPolicy::Restart => {
+                // The vote starts over from the validator's verdicts, and
+                // responses to the previous queries are ignored
+                let instance = state.instances.get_mut(&seq_num).unwrap();
+                instance.escalations += 1;
+                instance.round = 0;
+                instance.confidence_counter = 0;
+                instance.last_color = SnowballDecisionState::Undecided;
+                instance.decision_array.clear();
+                instance.response_buffer.clear();
+                instance.responders.clear();
+                instance.tips.clear();
+                instance.abstentions = 0;
+                instance.abandon_queries();
+                instance.phase = SnowballPhase::Validating;
+                instance.validation_timeout.start();
+                self.handle_pending_validation(state);
+            }
+            EscalationPolicy::Fail | EscalationPolicy::TieBreak => {
+                if self.escalations.contains_key(&seq_num) {
+                    return;
+                }
+                let mut timeout = Timeout::new(state.hang_timeout);
+                timeout.start();
+                self.escalations.insert(seq_num, timeout);
+
+                // A sync already in progress may have been sent before the
+                // peers decided
+                self.sync.timeout = None;
+                self.request_sync(state);
+            }
+        }
+    }
+
+    /// Apply the escalation policy to the instances which no peer reported a
+    /// decision for in time
+    pub fn check_escalations(&mut self, state: &mut SnowballState) {
+        let expired: Vec<u64> = self.escalations
+            .iter_mut()
+            .filter_map(|(seq_num, timeout)| if timeout.check_expired() { Some(*seq_num) } else { None })
+            .collect();
+
+        for seq_num in expired {
+            self.escalations.remove(&seq_num);
+            let running = state.instances
+                .get(&seq_num)
+                .map_or(false, |instance| instance.phase != SnowballPhase::Finishing);
+            if running {
+                self.decide_escalated(seq_num, state);
+            }
+        }
+    }
+
+    /// Decide an escalated instance locally, with the `Fail` or `TieBreak`
+    /// policy
+    fn decide_escalated(&mut self, seq_num: u64, state: &mut SnowballState) {
+        let decision = match state.escalation {
+            EscalationPolicy::TieBreak => {
+                let instance = &state.instances[&seq_num];
+                instance.decision_array
+                    .iter()
+                    .filter(|(color, _)| state.is_candidate(seq_num, color))
+                    .max_by(|(color_a, count_a), (color_b, count_b)| {
+                        let is_block = |color: &SnowballDecisionState| *color != SnowballDecisionState::KO;
+                        count_a
+                            .cmp(count_b)
+                            .then_with(|| is_block(color_a).cmp(&is_block(color_b)))
+                            .then_with(|| color_b.to_vote().1.cmp(&color_a.to_vote().1))
+                    })
+                    .map(|(color, _)| color.clone())
+                    .or_else(|| state.decision_map.get(&seq_num).cloned())
+                    .filter(|color| state.is_candidate(seq_num, color))
+                    .unwrap_or(SnowballDecisionState::KO)
+            }
+            _ => SnowballDecisionState::KO,
+        };
+
+        info!("Process {} decides {} for seq_num {} after escalating", state.order, decision, seq_num);
+        let instance = state.instances.get_mut(&seq_num).unwrap();
+        instance.escalations += 1;
+        instance.switch_phase();
+        state.decision_map.insert(seq_num, decision);
+        self.commit_decided_instances(state);
+    }
+
+    // ---------- Methods for catching up with peers ----------
+
+    /// Ask a sample of peers for the decisions taken from the first sequence
+    /// number this node hasn't decided yet
+    fn request_sync(&mut self, state: &mut SnowballState) {
+        if let Some(timeout) = self.sync.timeout.as_mut() {
+            if !timeout.check_expired() {
+                return;
+            }
+        }
+
+        let seq_num = state.first_undecided_seq_num();
+        info!("Process {} fell behind, requesting decisions from seq_num {}", state.order, seq_num);
+
+        self.sync = SyncProgress::default();
+        let sample = match self.select_node_sample(state, state.parameters().k as usize) {
+            Ok(sample) => sample,
+            Err(err) => {
+                error!("Process {} can't sample peers to catch up with: {}", state.order, err);
+                return;
+            }
+        };
+        for index in sample {
+            let peer_id = state.member_ids[index].clone();
+            self.send_message(&peer_id, PeerMessage::SyncRequest { seq_num }, state);
+            self.sync.peers.insert(peer_id);
+        }
+
+        let mut timeout = Timeout::new(state.hang_timeout);
+        timeout.start();
+        self.sync.timeout = Some(timeout);
+    }
+
+    /// Fast-forward through every decision reported by at least
+    /// `alfa_confidence` peers, in order of sequence number
+    fn apply_synced_decisions(&mut self, state: &mut SnowballState) {
+        let alfa = state.parameters().alfa_confidence;
+        loop {
+            let seq_num = state.first_undecided_seq_num();
+            let color = match self.sync.votes.get(&seq_num).and_then(|votes| {
+                votes
+                    .iter()
+                    .find(|(_, peers)| peers.len() as u64 >= alfa)
+                    .map(|(color, _)| color.clone())
+            }) {
+                Some(color) => color,
+                None => break,
+            };
+
+            if !self.fast_forward(seq_num, color, state) {
+                break;
+            }
+            self.sync.votes.remove(&seq_num);
+        }
+
+        self.try_initialize_block(state);
+    }
+
+    /// Apply the decision taken by the network for `seq_num`. Returns false
+    /// when the decided block is not known to the validator yet, or it can't
+    /// be committed.
+    fn fast_forward(&mut self, seq_num: u64, color: SnowballDecisionState, state: &mut SnowballState) -> bool {
+        let block = match &color {
+            SnowballDecisionState::OK(block_id) => match self.service.get_blocks(vec![block_id.clone()]) {
+                Ok(mut blocks) => match blocks.remove(block_id) {
+                    Some(block) => Some(block),
+                    None => return false,
+                },
+                Err(err) => {
+                    warn!("Block {} decided for seq_num {} is not available: {:?}", hex::encode(block_id), seq_num, err);
+                    return false;
+                }
+            },
+            _ => None,
+        };
+
+        info!("Process {} catching up on seq_num {} with decision {}", state.order, seq_num, color);
+
+        if state.instances.contains_key(&seq_num) {
+            // A running instance is closed with the decision of the network
+            if let Some(block) = &block {
+                if !state.measurements.convergenza.contains_key(&block.block_id) {
+                    state.set_block_new_timestamp(block.block_id.clone());
+                }
+            }
+            let instance = state.instances.get_mut(&seq_num).unwrap();
+            if let Some(block) = block {
+                if !instance.candidate_blocks.contains(&block.block_id) {
+                    instance.candidate_parents.insert(block.block_id.clone(), block.previous_id);
+                    instance.candidate_blocks.push(block.block_id);
+                }
+            }
+            instance.abandon_queries();
+            instance.phase = SnowballPhase::Finishing;
+            state.decision_map.insert(seq_num, color);
+            self.commit_decided_instances(state);
+        } else {
+            // The instance was never started here
+            if let Some(block) = block {
+                // The chain head only follows the validator's, the decision
+                // is requested again once the sync timeout expires
+                if let Err(err) = self.service.commit_block(block.block_id.clone()) {
+                    error!("Couldn't commit block due to error: {:?}", err);
+                    self.request_sync(state);
+                    return false;
                 }
-                if state.confidence_counter >= state.beta {
-                    state.switch_phase();
-                    self.handle_decision(state);
+                // The block is final now, it must not be failed once dequeued
+                self.block_queue.retain(|queued| queued.block_id != block.block_id);
+                self.held_blocks.retain(|held| held.block_id != block.block_id);
+                state.chain_head = block.block_id;
+                state.chain_head_block_num = block.block_num;
+                self.reset_view(state);
+            }
+            state.seq_num = seq_num;
+            state.decision_map.insert(seq_num, color);
+            self.drop_outdated_block(seq_num);
+            self.on_progress(state);
+        }
+
+        true
+    }
+
+    // ---------- Methods for replacing a silent proposer ----------
+
+    /// Return to the original proposer schedule once a block is committed.
+    /// Every node commits the same blocks, so they all leave a view at the
+    /// same point of the chain. Replaced proposers stay out of the schedule.
+    fn reset_view(&mut self, state: &mut SnowballState) {
+        if state.view != 0 {
+            info!("Process {} returns from view {} to view 0", state.order, state.view);
+            state.view = 0;
+        }
+    }
+
+    /// Restart waiting for the proposer, and drop a failover vote about an
+    /// instance which started anyway
+    fn on_progress(&mut self, state: &SnowballState) {
+        self.idle_timeout.start();
+        let outdated = match &self.failover {
+            Some(failover) => failover.seq_num <= state.seq_num,
+            None => false,
+        };
+        if outdated {
+            info!("Process {} stops the failover vote, the proposer showed up", state.order);
+            self.failover = None;
+        }
+    }
+
+    /// Start a failover vote when the next instance doesn't start in time,
+    /// and close the rounds of a running vote whose peers didn't all answer
+    pub fn check_idle(&mut self, state: &mut SnowballState) {
+        if let Some(failover) = self.failover.as_mut() {
+            if failover.round_timeout.check_expired() {
+                self.on_failover_round_end(state);
+            }
+            return;
+        }
+
+        // Only an instance which the window has room for can be late, and
+        // its proposer has no reason to replace itself
+        let seq_num = state.seq_num + 1;
+        if !self.idle_timeout.check_expired() || state.instances.len() as u64 >= state.window {
+            return;
+        }
+        if let Some(previous_id) = state.get_tip() {
+            if state.is_proposer(seq_num, &previous_id) {
+                self.idle_timeout.start();
+                return;
+            }
+        }
+
+        warn!(
+            "Process {} received no block for seq_num {} in view {}, voting on a new proposer",
+            state.order, seq_num, state.view
+        );
+        self.failover = Some(FailoverVote {
+            seq_num,
+            preference: state.view + 1,
+            last_majority: None,
+            confidence_counter: 0,
+            decision_array: HashMap::new(),
+            response_buffer: HashMap::new(),
+            waiting: HashSet::new(),
+            round_timeout: Timeout::new(state.hang_timeout),
+        });
+        self.start_failover_round(state);
+    }
+
+    /// Ask a sample of peers for the view they prefer
+    fn start_failover_round(&mut self, state: &mut SnowballState) {
+        let sample = match self.select_node_sample(state, state.parameters().k as usize) {
+            Ok(sample) => sample,
+            Err(err) => {
+                error!("Process {} can't sample peers for the failover vote: {}", state.order, err);
+                return;
+            }
+        };
+
+        let (seq_num, view) = {
+            let failover = self.failover.as_mut().unwrap();
+            failover.response_buffer.clear();
+            failover.waiting = sample.iter().map(|index| state.member_ids[*index].clone()).collect();
+            failover.round_timeout.start();
+            (failover.seq_num, failover.preference)
+        };
+
+        for index in sample {
+            let peer_id = state.member_ids[index].clone();
+            self.send_message(&peer_id, PeerMessage::FailoverRequest { seq_num, view }, state);
+        }
+    }
+
+    /// Apply the Snowball update rule to the views collected in a round, and
+    /// switch to the agreed view once it is confirmed `beta` times in a row.
+    /// Peers which didn't answer count as votes for no view.
+    fn on_failover_round_end(&mut self, state: &mut SnowballState) {
+        let params = state.parameters();
+        let decided = {
+            let failover = match self.failover.as_mut() {
+                Some(failover) => failover,
+                None => return,
+            };
+            let majority = failover.response_buffer
+                .iter()
+                .find(|(_, count)| **count >= params.alfa_preference)
+                .map(|(view, _)| *view);
+
+            match majority {
+                Some(view) => {
+                    let wins = {
+                        let wins = failover.decision_array.entry(view).or_insert(0);
+                        *wins += 1;
+                        *wins
+                    };
+                    if wins > failover.decision_array.get(&failover.preference).cloned().unwrap_or(0) {
+                        failover.preference = view;
+                    }
+                    if failover.last_majority == Some(view) {
+                        failover.confidence_counter += 1;
+                    } else {
+                        failover.last_majority = Some(view);
+                        failover.confidence_counter = 1;
+                    }
                 }
-                else {
-                    let sample = self.select_node_sample(state, state.k as usize);
-                    self.prepare_and_forward_peer_requests(sample, state);
+                None => failover.confidence_counter = 0,
+            }
+
+            if failover.confidence_counter >= params.beta {
+                Some((failover.seq_num, failover.preference))
+            } else {
+                None
+            }
+        };
+
+        match decided {
+            Some((seq_num, view)) => {
+                // Views advance one at a time, so a minority can't skip
+                // several proposers at once
+                if view == state.view + 1 {
+                    let previous_id = state.get_tip().unwrap_or_else(|| state.preferred_tip());
+                    self.switch_view(seq_num, &previous_id, state);
+                } else {
+                    warn!("Process {} ignores the failover vote for view {} in view {}", state.order, view, state.view);
                 }
+                self.failover = None;
+                self.idle_timeout.start();
+                self.try_initialize_block(state);
             }
+            None => self.start_failover_round(state),
+        }
+    }
+
+    /// Replace the proposer of instance `seq_num` by the one of the next view
+    fn switch_view(&mut self, seq_num: u64, previous_id: &BlockId, state: &mut SnowballState) {
+        info!("Process {} switches from view {} to view {}", state.order, state.view, state.view + 1);
+        state.replace_proposer(seq_num, previous_id);
+        self.supported_view = None;
+    }
+
+    /// A node which supported the next view in the failover vote of a peer
+    /// switches to it once the block of the new proposer arrives: the vote
+    /// was decided, since the new proposer only builds a block afterwards
+    fn adopt_supported_view(&mut self, block: &Block, seq_num: u64, state: &mut SnowballState) {
+        if self.supported_view != Some((seq_num, state.view + 1)) {
+            return;
         }
-        if !majority {
-            state.confidence_counter = 0;
-            let sample = self.select_node_sample(state, state.k as usize);
-            self.prepare_and_forward_peer_requests(sample, state);
+        if block.signer_id != state.get_next_proposer(seq_num, &block.previous_id) {
+            return;
         }
+
+        self.switch_view(seq_num, &block.previous_id, state);
+        self.failover = None;
+        self.idle_timeout.start();
     }
 
     // ---------- Methods for handling state changes ----------
 
-    pub fn handle_block_new(&mut self, block_id: BlockId, state: &mut SnowballState) {
-        state.decision_block = block_id;
+    pub fn handle_block_new(&mut self, block: Block, state: &mut SnowballState) {
         state.seq_num += 1;
+        let seq_num = state.seq_num;
 
-        // algorithm starts on block new message
-        let my_decision = SnowballDecisionState::OK;
+        self.drop_outdated_block(seq_num);
+        self.on_progress(state);
 
-        state.decision_map.insert(state.seq_num, my_decision.clone());
-        state.last_color = my_decision.clone();
-        state.confidence_counter = 0;
-        state.decision_array = [0, 0];
+        // algorithm starts on block new message, but the initial preference
+        // waits for the validator's verdict
+        let params = state.parameters();
+        if state.adaptive {
+            info!("Process {} using {:?} for seq_num {}", state.order, params, seq_num);
+        }
+        let mut instance = SnowballInstance::new(seq_num, block.block_num, params, state.validation_timeout);
+        instance.candidate_parents.insert(block.block_id.clone(), block.previous_id);
+        instance.candidate_blocks.push(block.block_id);
+        instance.validation_timeout.start();
+        instance.switch_phase();
 
-        let sample = self.select_node_sample(state, state.k as usize);
-        self.prepare_and_forward_peer_requests(sample, state);
+        state.instances.insert(seq_num, instance);
+        state.decision_map.insert(seq_num, SnowballDecisionState::Undecided);
 
-        state.switch_phase();
+        self.handle_pending_validation(state);
     }
 
-    pub fn handle_decision(&mut self, state: &mut SnowballState) {
-        let mut decision_u8 = 0;
-        let decision = state.decision_map.get(&state.seq_num).unwrap();
-        info!("Process {} deciding {} for block seq {}", state.order, decision, state.seq_num);
-        
-        if *decision == SnowballDecisionState::OK {
-            decision_u8 = 1;
-            self.service
-                .commit_block(state.decision_block.clone())
-                .expect("Failed to commit block");
-            state.chain_head = state.decision_block.clone();
+    /// A block still being built for `seq_num` or earlier can't be published anymore
+    fn drop_outdated_block(&mut self, seq_num: u64) {
+        let outdated = match &self.initialized_block {
+            Some((initialized_seq_num, _)) => *initialized_seq_num <= seq_num,
+            None => false,
+        };
+        if outdated {
+            self.cancel_block();
+            self.initialized_block = None;
         }
-        else {
-            decision_u8 = 0;
-            self.service
-                .fail_block(state.decision_block.clone())
-                .expect("Failed to fail block");
+    }
+
+    /// Decisions are committed in order: every decided instance at the front
+    /// of the window is handled, until one still running is found
+    pub fn commit_decided_instances(&mut self, state: &mut SnowballState) {
+        loop {
+            let seq_num = match state.instances.values().next() {
+                Some(instance) if instance.phase == SnowballPhase::Finishing => instance.seq_num,
+                _ => break,
+            };
+            self.handle_decision(seq_num, state);
+        }
+
+        self.try_initialize_block(state);
+    }
+
+    pub fn handle_decision(&mut self, seq_num: u64, state: &mut SnowballState) {
+        let mut instance = state.instances.remove(&seq_num).unwrap();
+        let decision = state.decision_map.get(&seq_num).cloned().unwrap();
+        info!("Process {} deciding {} for block seq {}", state.order, decision, seq_num);
+        self.escalations.remove(&seq_num);
+        self.on_progress(state);
+
+        // The winner is committed, every other candidate is failed
+        let winner = match &decision {
+            SnowballDecisionState::OK(block_id) => Some(block_id.clone()),
+            _ => None,
+        };
+        let mut failed_blocks = HashSet::new();
+        for block_id in instance.candidate_blocks.clone() {
+            // Certificates embedded in a committed block are in the chain for good
+            let embedded = self.embedded_certificates.remove(&block_id).unwrap_or_default();
+            if Some(&block_id) == winner.as_ref() {
+                for embedded_seq_num in embedded {
+                    state.pending_certificates.remove(&embedded_seq_num);
+                }
+
+                self.service
+                    .commit_block(block_id.clone())
+                    .expect("Failed to commit block");
+                state.chain_head = block_id;
+                state.chain_head_block_num = instance.decision_block_num;
+                self.reset_view(state);
+            }
+            else {
+                self.fail_block(block_id.clone(), instance.decision_block_num, state);
+                failed_blocks.insert(block_id);
+            }
+        }
+
+        // Descendants of blocks below the chain head are stale anyway
+        let chain_head_block_num = state.chain_head_block_num;
+        state.failed_blocks.retain(|_, block_num| *block_num >= chain_head_block_num);
+        let decision_u8 = if winner.is_some() { 1 } else { 0 };
+
+        // KO decisions are measured on the first candidate of the instance
+        let decided_block = winner.unwrap_or_else(|| instance.candidate_blocks[0].clone());
+        for block_id in &instance.candidate_blocks {
+            state.block_validation.remove(block_id);
+            if *block_id != decided_block {
+                state.measurements.convergenza.remove(block_id);
+            }
         }
 
-        let elapsed = state.set_block_commit_timestamp(state.decision_block.clone());
+        self.reject_descendants(&failed_blocks, state);
+
+        let elapsed = state.set_block_commit_timestamp(decided_block.clone());
+
+        state.pending_certificates.insert(seq_num, FinalityCertificate {
+            seq_num,
+            block_id: decided_block.clone(),
+            block_num: instance.decision_block_num,
+            vote: decision_u8,
+            confidence: instance.confidence_counter,
+            round: instance.round,
+            response_buffer: instance.response_buffer
+                .iter()
+                .map(|(color, count)| {
+                    let (vote, block_id) = color.to_vote();
+                    CertifiedVote { vote, block_id, count: *count }
+                })
+                .collect(),
+            responders: instance.responders.clone(),
+        });
+        while state.pending_certificates.len() > MAX_PENDING_CERTIFICATES {
+            let oldest = *state.pending_certificates.keys().next().unwrap();
+            warn!("Process {} dropping the certificate of seq_num {}, which no committed block carries", state.order, oldest);
+            state.pending_certificates.remove(&oldest);
+        }
 
         let mut log_message = LogMessage::new();
-        log_message.block_id = hex::encode(state.decision_block.clone());
+        log_message.block_id = hex::encode(decided_block);
+        log_message.n_candidates = instance.candidate_blocks.len() as u64;
         log_message.n_messages = state.measurements.n_messaggi_inviati;
+        log_message.n_unknown_messages = state.measurements.unknown_messages;
+        log_message.n_replayed_messages = state.measurements.replayed_messages;
+        log_message.n_late_responses = state.peer_stats.values().map(|stats| stats.late_responses).sum();
         log_message.elapsed_time = elapsed;
         log_message.n_members = state.member_ids.len() as u64;
-        log_message.seq_num = state.seq_num;
+        log_message.total_stake = state.stake.values().sum();
+        log_message.seq_num = seq_num;
         log_message.alfa = state.alfa;
-        log_message.beta = state.beta;
-        log_message.k = state.k;
+        log_message.alfa_preference = instance.params.alfa_preference;
+        log_message.alfa_confidence = instance.params.alfa_confidence;
+        log_message.beta = instance.params.beta;
+        log_message.k = instance.params.k;
+        log_message.adaptive = state.adaptive;
+        log_message.window = state.window;
+        log_message.escalation = state.escalation.to_string();
+        log_message.escalations = instance.escalations;
+        log_message.abstentions = instance.total_abstentions;
+        log_message.variant = state.variant.to_string();
         log_message.order = state.order;
         log_message.byzantine = state.byzantine_test.clone();
         log_message.decision = decision_u8;
@@ -479,16 +1880,59 @@ impl SnowballNode {
             .json(&serde_json::to_string(&log_message).unwrap())
             .send();
 
-        self.block_queue.pop_front();
+        instance.switch_phase();
+    }
+
+    /// Mark as invalid the candidates of running instances built on top of a
+    /// failed block, and drop the block being built on top of one
+    fn reject_descendants(&mut self, failed_blocks: &HashSet<BlockId>, state: &mut SnowballState) {
+        if failed_blocks.is_empty() {
+            return;
+        }
+
+        let mut rejected = HashSet::new();
+        for instance in state.instances.values() {
+            for (block_id, previous_id) in &instance.candidate_parents {
+                if failed_blocks.contains(previous_id) {
+                    warn!("Block {} for seq_num {} extends a failed block", hex::encode(block_id), instance.seq_num);
+                    rejected.insert(block_id.clone());
+                }
+            }
+        }
+
+        let abandoned = match &self.initialized_block {
+            Some((_, previous_id)) => failed_blocks.contains(previous_id),
+            None => false,
+        };
+        if abandoned {
+            self.cancel_block();
+            self.initialized_block = None;
+        }
 
-        // TODO: GENERALIZE: ONLY FIRST NODE CAN PROPOSE
-        if state.order == 0 {
-            self.service.initialize_block(None).unwrap_or_else(|err| {
-                error!("Couldn't initialize block due to error: {}", err)
-            });
+        // Instances preferring a rejected block fall back to another
+        // candidate, or to KO when none is left
+        for instance in state.instances.values() {
+            let rejected_preference = match state.decision_map.get(&instance.seq_num) {
+                Some(SnowballDecisionState::OK(block_id)) => rejected.contains(block_id),
+                _ => false,
+            };
+            if rejected_preference {
+                let fallback = instance.candidate_blocks
+                    .iter()
+                    .find(|block_id| !rejected.contains(*block_id) && state.block_validation.get(*block_id) != Some(&false))
+                    .cloned()
+                    .map_or(SnowballDecisionState::KO, SnowballDecisionState::OK);
+                state.decision_map.insert(instance.seq_num, fallback);
+            }
         }
 
-        state.switch_phase();
+        for block_id in rejected.iter() {
+            state.block_validation.insert(block_id.clone(), false);
+        }
+        if !rejected.is_empty() {
+            self.handle_pending_validation(state);
+            self.reject_descendants(&rejected, state);
+        }
     }
 
     // ---------- Helper methods ----------
@@ -498,16 +1942,10 @@ impl SnowballNode {
         step.sample(&mut self.rng)
     }
 
-    pub fn select_node_sample(&mut self, state: &mut SnowballState, amount: usize) -> HashSet<usize> {
-        let mut set = HashSet::<usize>::with_capacity(amount);
-        while set.len() < amount as usize {
-            let choice = self.random_value(state.member_ids.len());
-            if choice != state.order as usize {
-                set.insert(choice);
-            }
-        }
+    pub fn select_node_sample(&mut self, state: &SnowballState, amount: usize) -> Result<HashSet<usize>, SampleError> {
+        let set = self.sampler.sample(state, &HashSet::new(), amount)?;
         debug!("Set for node {:?}: {:?}", state.order, set);
-        set
+        Ok(set)
     }
 
     pub fn handle_unresponsive_peers(&mut self, state: &mut SnowballState) {
@@ -517,41 +1955,110 @@ impl SnowballNode {
             return;
         }
 
-        let mut expired = HashSet::new();
-        for (peer_id, timeout) in &state.waiting_response_map {
-            if timeout.clone().check_expired() {
-                warn!("Expired timeout without a response from {}", hex::encode(peer_id));
-                expired.insert(peer_id.clone());
+        let mut expired = Vec::new();
+        for instance in state.instances.values() {
+            for (peer_id, pending) in &instance.waiting_response_map {
+                if pending.timeout.clone().check_expired() {
+                    warn!("Expired timeout without a response from {} for seq_num {}", hex::encode(peer_id), instance.seq_num);
+                    expired.push((instance.seq_num, peer_id.clone()));
+                }
             }
         }
 
-        let mut amount_to_add = 0;
-        for peer_id in expired {
-            state.waiting_response_map.remove(&peer_id);
-            amount_to_add += 1;
+        for (seq_num, peer_id) in expired {
+            if let Some(instance) = state.instances.get_mut(&seq_num) {
+                instance.abandon_query(&peer_id);
+            }
+            state.record_timeout(&peer_id);
+            self.request_replacement_peer(seq_num, state);
         }
+    }
 
-        // I find another node to send a request to, which is not in my
-        // current waiting response set
-        for _ in 0..amount_to_add {
-            let mut id = Vec::new();
-            let missing_responses_len = state.waiting_response_map.len();
-            while state.waiting_response_map.len() < missing_responses_len + 1 {
-                let extra_node_set = self.select_node_sample(state, 1);
-                for extra_node_index in extra_node_set {
-                    id = state.member_ids[extra_node_index].clone();
-                    state.add_to_waiting_set(id.clone());
-                }
-            }
-            info!("Sending additional peer notifications to {:?}.", hex::encode(&id));
-            self.send_peer_notification(&id, "request", state.seq_num, state);            
+}
+
+/// Decode the consensus field of a block and check it against the block: the
+/// summary must match the one computed by the validator and the certificates
+/// must be consistent. While `legacy_blocks` is set, the bare summary written
+/// by nodes which predate the payload is accepted as well.
+fn check_consensus(block: &Block, legacy_blocks: bool) -> Result<(), String> {
+    let consensus = match ConsensusPayload::decode(&block.payload) {
+        Ok(consensus) => consensus,
+        Err(_) if legacy_blocks && block.payload == create_legacy_consensus(&block.summary) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if consensus.version != CONSENSUS_VERSION {
+        return Err(format!("unsupported version {}", consensus.version));
+    }
+
+    if consensus.summary != block.summary {
+        return Err(String::from("summary doesn't match the block contents"));
+    }
+
+    for certificate in consensus.certificates {
+        let votes: u64 = certificate.response_buffer.iter().map(|vote| vote.count).sum();
+        if certificate.vote > 1 || votes != certificate.responders.len() as u64 {
+            return Err(format!("inconsistent certificate for seq_num {}", certificate.seq_num));
+        }
+        if certificate.block_num > block.block_num {
+            return Err(format!("certificate for block_num {} is newer than the block", certificate.block_num));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `block` is one block above its parent
+fn check_block_num(block: &Block, state: &SnowballState) -> Result<(), String> {
+    // The height of parents which are neither the chain head nor a candidate
+    // isn't known yet, and their children are held anyway
+    let parent_block_num = if block.previous_id == state.chain_head {
+        Some(state.chain_head_block_num)
+    } else {
+        state.instances
+            .values()
+            .find(|instance| instance.candidate_blocks.contains(&block.previous_id))
+            .map(|instance| instance.decision_block_num)
+    };
+    if let Some(parent_block_num) = parent_block_num {
+        if block.block_num != parent_block_num + 1 {
+            return Err(format!("block_num {} doesn't follow its parent at {}", block.block_num, parent_block_num));
         }
     }
 
+    Ok(())
 }
 
-fn create_consensus(summary: &[u8]) -> Vec<u8> {
-    let mut consensus: Vec<u8> = Vec::from(&b"Snowball"[..]);
+/// Consensus field of the blocks published before the consensus payload
+fn create_legacy_consensus(summary: &[u8]) -> Vec<u8> {
+    let mut consensus = Vec::from(CONSENSUS_PREFIX);
     consensus.extend_from_slice(summary);
     consensus
 }
+
+fn create_consensus(summary: &[u8], certificates: Vec<FinalityCertificate>) -> Vec<u8> {
+    ConsensusPayload {
+        version: CONSENSUS_VERSION,
+        summary: summary.to_vec(),
+        certificates,
+    }.encode()
+}
+
+/// Load the keypair configured for the node. Without one, messages are sent
+/// unsigned, so startup fails when peers verify signatures.
+fn load_signing_key(config: &SnowballConfig) -> Option<SecretSignKey> {
+    let enforced = !config.public_keys.is_empty();
+    let path = match &config.signing_key_path {
+        Some(path) => path,
+        None if enforced => panic!("Signatures are verified by peers, but no signing key was configured"),
+        None => return None,
+    };
+
+    match signing::load_or_create_signing_key(Path::new(path)) {
+        Ok(secret_key) => Some(secret_key),
+        Err(err) if enforced => panic!("Unable to load signing key from {}: {}", path, err),
+        Err(err) => {
+            error!("Unable to load signing key from {}: {}", path, err);
+            None
+        }
+    }
+}
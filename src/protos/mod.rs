@@ -0,0 +1,3 @@
+//! Types generated from the protos at build time
+
+include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
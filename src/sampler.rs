@@ -0,0 +1,290 @@
+//! Strategies for choosing the peers queried by a Snowball node
+
+use std::collections::HashSet;
+use std::fmt;
+
+use rand;
+use rand::distributions::{Distribution, Uniform};
+use rand::seq::SliceRandom;
+
+use crate::config::SamplingStrategy;
+use crate::state::SnowballState;
+
+/// Raised when a sample can't be drawn from the eligible peers
+#[derive(Debug)]
+pub enum SampleError {
+    /// Fewer peers than requested are eligible
+    NotEnoughPeers { requested: usize, available: usize },
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleError::NotEnoughPeers { requested, available } => write!(
+                f,
+                "requested {} peers, but only {} are eligible",
+                requested, available
+            ),
+        }
+    }
+}
+
+/// Chooses which members to query. Samples are drawn without replacement and
+/// never contain the local node.
+pub trait PeerSampler {
+    /// Draw `amount` distinct members, as indexes into `state.member_ids`,
+    /// none of which is in `exclude`
+    fn sample(
+        &mut self,
+        state: &SnowballState,
+        exclude: &HashSet<usize>,
+        amount: usize,
+    ) -> Result<HashSet<usize>, SampleError>;
+}
+
+/// Build the sampler implementing `strategy`
+pub fn new_sampler(strategy: SamplingStrategy) -> Box<dyn PeerSampler> {
+    match strategy {
+        SamplingStrategy::Uniform => Box::new(UniformSampler::new()),
+        SamplingStrategy::ExcludeAwaited => Box::new(ExcludeAwaitedSampler::new()),
+        SamplingStrategy::Stake => Box::new(StakeSampler::new()),
+        SamplingStrategy::Reputation => Box::new(ReputationSampler::new()),
+    }
+}
+
+/// Every eligible member has the same probability of being sampled
+pub struct UniformSampler {
+    rng: rand::rngs::ThreadRng,
+}
+
+impl UniformSampler {
+    pub fn new() -> Self {
+        UniformSampler {
+            rng: rand::thread_rng(),
+        }
+    }
+}
+
+impl PeerSampler for UniformSampler {
+    fn sample(
+        &mut self,
+        state: &SnowballState,
+        exclude: &HashSet<usize>,
+        amount: usize,
+    ) -> Result<HashSet<usize>, SampleError> {
+        let eligible = eligible_members(state, exclude);
+        if eligible.len() < amount {
+            return Err(SampleError::NotEnoughPeers {
+                requested: amount,
+                available: eligible.len(),
+            });
+        }
+
+        Ok(eligible.choose_multiple(&mut self.rng, amount).cloned().collect())
+    }
+}
+
+/// Uniform sampling which skips the members that still owe a response to any
+/// running instance, spreading the queries over idle peers
+pub struct ExcludeAwaitedSampler {
+    uniform: UniformSampler,
+}
+
+impl ExcludeAwaitedSampler {
+    pub fn new() -> Self {
+        ExcludeAwaitedSampler {
+            uniform: UniformSampler::new(),
+        }
+    }
+}
+
+impl PeerSampler for ExcludeAwaitedSampler {
+    fn sample(
+        &mut self,
+        state: &SnowballState,
+        exclude: &HashSet<usize>,
+        amount: usize,
+    ) -> Result<HashSet<usize>, SampleError> {
+        let mut exclude = exclude.clone();
+        for (index, peer_id) in state.member_ids.iter().enumerate() {
+            let awaited = state.instances
+                .values()
+                .any(|instance| instance.waiting_response_map.contains_key(peer_id));
+            if awaited {
+                exclude.insert(index);
+            }
+        }
+
+        self.uniform.sample(state, &exclude, amount)
+    }
+}
+
+/// Members are sampled in proportion to their stake; members without stake
+/// are never sampled
+pub struct StakeSampler {
+    rng: rand::rngs::ThreadRng,
+}
+
+impl StakeSampler {
+    pub fn new() -> Self {
+        StakeSampler {
+            rng: rand::thread_rng(),
+        }
+    }
+}
+
+impl PeerSampler for StakeSampler {
+    fn sample(
+        &mut self,
+        state: &SnowballState,
+        exclude: &HashSet<usize>,
+        amount: usize,
+    ) -> Result<HashSet<usize>, SampleError> {
+        let weights = eligible_members(state, exclude)
+            .into_iter()
+            .map(|index| {
+                let stake = state.stake.get(&state.member_ids[index]).cloned().unwrap_or(0);
+                (index, stake as f64)
+            })
+            .collect();
+
+        weighted_sample(&mut self.rng, weights, amount)
+    }
+}
+
+/// Members are sampled in proportion to their reputation, so that peers which
+/// misbehave are queried less often
+pub struct ReputationSampler {
+    rng: rand::rngs::ThreadRng,
+}
+
+impl ReputationSampler {
+    pub fn new() -> Self {
+        ReputationSampler {
+            rng: rand::thread_rng(),
+        }
+    }
+}
+
+impl PeerSampler for ReputationSampler {
+    fn sample(
+        &mut self,
+        state: &SnowballState,
+        exclude: &HashSet<usize>,
+        amount: usize,
+    ) -> Result<HashSet<usize>, SampleError> {
+        let weights = eligible_members(state, exclude)
+            .into_iter()
+            .map(|index| (index, state.reputation(&state.member_ids[index])))
+            .collect();
+
+        weighted_sample(&mut self.rng, weights, amount)
+    }
+}
+
+/// Indexes of the members other than the local node which aren't excluded
+fn eligible_members(state: &SnowballState, exclude: &HashSet<usize>) -> Vec<usize> {
+    (0..state.member_ids.len())
+        .filter(|index| *index != state.order as usize && !exclude.contains(index))
+        .collect()
+}
+
+/// Draw `amount` distinct indexes, each with probability proportional to its
+/// weight among the ones not drawn yet. Entries without weight are ignored.
+fn weighted_sample(
+    rng: &mut rand::rngs::ThreadRng,
+    weights: Vec<(usize, f64)>,
+    amount: usize,
+) -> Result<HashSet<usize>, SampleError> {
+    let mut weights: Vec<(usize, f64)> = weights
+        .into_iter()
+        .filter(|(_, weight)| *weight > 0.0)
+        .collect();
+    if weights.len() < amount {
+        return Err(SampleError::NotEnoughPeers {
+            requested: amount,
+            available: weights.len(),
+        });
+    }
+
+    let mut set = HashSet::with_capacity(amount);
+    while set.len() < amount {
+        let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+        let mut point = Uniform::new(0.0, total).sample(rng);
+
+        // Rounding may leave the point past the last weight, which then wins
+        let position = weights
+            .iter()
+            .position(|(_, weight)| {
+                if point < *weight {
+                    true
+                } else {
+                    point -= weight;
+                    false
+                }
+            })
+            .unwrap_or(weights.len() - 1);
+        let (index, _) = weights.swap_remove(position);
+        set.insert(index);
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SnowballConfig;
+
+    /// State of the first member of a network with `size` members
+    fn state_of_first_member(size: u8) -> SnowballState {
+        let mut config = SnowballConfig::default();
+        config.members = (0..size).map(|id| vec![id]).collect();
+        SnowballState::new(vec![0], 0, &config)
+    }
+
+    fn indexes(indexes: &[usize]) -> HashSet<usize> {
+        indexes.iter().cloned().collect()
+    }
+
+    #[test]
+    fn samples_skip_self_and_excluded() {
+        let state = state_of_first_member(5);
+        let exclude = indexes(&[1, 2]);
+
+        for strategy in &[SamplingStrategy::Uniform, SamplingStrategy::ExcludeAwaited, SamplingStrategy::Reputation] {
+            let mut sampler = new_sampler(*strategy);
+            for _ in 0..20 {
+                let sample = sampler.sample(&state, &exclude, 2).unwrap();
+                assert_eq!(sample, indexes(&[3, 4]), "{:?} sample", strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn stake_sampling_skips_members_without_stake() {
+        let mut state = state_of_first_member(4);
+        state.stake.insert(vec![2], 10);
+
+        let mut sampler = StakeSampler::new();
+        for _ in 0..20 {
+            assert_eq!(sampler.sample(&state, &HashSet::new(), 1).unwrap(), indexes(&[2]));
+        }
+        match sampler.sample(&state, &HashSet::new(), 2) {
+            Err(SampleError::NotEnoughPeers { requested: 2, available: 1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn not_enough_peers() {
+        let state = state_of_first_member(3);
+        let exclude = indexes(&[1]);
+
+        for strategy in &[SamplingStrategy::Uniform, SamplingStrategy::ExcludeAwaited, SamplingStrategy::Reputation] {
+            match new_sampler(*strategy).sample(&state, &exclude, 2) {
+                Err(SampleError::NotEnoughPeers { requested: 2, available: 1 }) => {}
+                other => panic!("{:?} returned {:?}", strategy, other),
+            }
+        }
+    }
+}
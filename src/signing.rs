@@ -0,0 +1,162 @@
+//! Keys used to sign the messages exchanged by Snowball nodes
+//!
+//! The consensus engine API doesn't give engines access to the validator's
+//! key, nor a way to sign with it, so each engine has a keypair of its own.
+//! Members register its public key on-chain next to their validator id.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use atomicwrites::{AllowOverwrite, AtomicFile};
+use safe_crypto::{gen_sign_keypair, PublicSignKey, SecretSignKey, Signature};
+use safe_crypto::{PUBLIC_SIGN_KEY_BYTES, SECRET_SIGN_KEY_BYTES, SIGNATURE_BYTES};
+use sawtooth_sdk::consensus::engine::PeerId;
+
+/// Keypair of the local node, stored as JSON with hex encoded keys
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    public_key: String,
+    secret_key: String,
+}
+
+/// Signs outgoing messages and verifies incoming ones. Verification is only
+/// enforced once public keys are registered on-chain.
+pub struct MessageSigner {
+    secret_key: Option<SecretSignKey>,
+    public_keys: HashMap<PeerId, PublicSignKey>,
+}
+
+impl MessageSigner {
+    pub fn new(secret_key: Option<SecretSignKey>, public_keys: HashMap<PeerId, PublicSignKey>) -> Self {
+        MessageSigner {
+            secret_key,
+            public_keys,
+        }
+    }
+
+    /// Whether peers must sign their messages
+    pub fn enforced(&self) -> bool {
+        !self.public_keys.is_empty()
+    }
+
+    /// Signature of `data`, or nothing when the node has no key
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match &self.secret_key {
+            Some(secret_key) => secret_key.sign_detached(data).into_bytes().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Check that `signature` was made on `data` with the key registered for
+    /// `peer_id`
+    pub fn verify(&self, peer_id: &PeerId, data: &[u8], signature: &[u8]) -> Result<(), String> {
+        let public_key = self
+            .public_keys
+            .get(peer_id)
+            .ok_or_else(|| String::from("no public key registered"))?;
+
+        if signature.len() != SIGNATURE_BYTES {
+            return Err(format!("signature is {} bytes long", signature.len()));
+        }
+        let mut bytes = [0; SIGNATURE_BYTES];
+        bytes.copy_from_slice(signature);
+
+        if public_key.verify_detached(&Signature::from_bytes(bytes), data) {
+            Ok(())
+        } else {
+            Err(String::from("invalid signature"))
+        }
+    }
+}
+
+/// Load the keypair of the node from `path`, generating it on first use. The
+/// public key is logged so that it can be registered on-chain.
+pub fn load_or_create_signing_key(path: &Path) -> Result<SecretSignKey, String> {
+    if path.exists() {
+        let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let key_file: KeyFile = serde_json::from_str(&contents).map_err(|err| err.to_string())?;
+        info!("Loaded signing key with public key {}", key_file.public_key);
+
+        let mut secret_key = [0; SECRET_SIGN_KEY_BYTES];
+        secret_key.copy_from_slice(&decode_key(&key_file.secret_key, SECRET_SIGN_KEY_BYTES)?);
+        return Ok(SecretSignKey::from_bytes(secret_key));
+    }
+
+    let (public_key, secret_key) = gen_sign_keypair();
+    let key_file = KeyFile {
+        public_key: hex::encode(&public_key.into_bytes()[..]),
+        secret_key: hex::encode(&secret_key.clone().into_bytes()[..]),
+    };
+    let contents = serde_json::to_string(&key_file).map_err(|err| err.to_string())?;
+    AtomicFile::new(path, AllowOverwrite)
+        .write(|f| f.write_all(contents.as_bytes()))
+        .map_err(|err| err.to_string())?;
+    warn!("Generated signing key with public key {}; register it in 'sawtooth.consensus.algorithm.public_keys'", key_file.public_key);
+
+    Ok(secret_key)
+}
+
+/// Parse the public keys setting, a JSON map of hex encoded member ids to hex
+/// encoded public keys
+pub fn get_public_keys_from_setting(setting: &str) -> Result<HashMap<PeerId, PublicSignKey>, String> {
+    let public_keys: HashMap<String, String> = serde_json::from_str(setting).map_err(|err| err.to_string())?;
+
+    public_keys
+        .into_iter()
+        .map(|(id, public_key)| {
+            let peer_id = hex::decode(&id).map_err(|err| format!("invalid member id {}: {:?}", id, err))?;
+            let bytes = decode_key(&public_key, PUBLIC_SIGN_KEY_BYTES)
+                .map_err(|err| format!("invalid public key of {}: {}", id, err))?;
+            let mut public_key = [0; PUBLIC_SIGN_KEY_BYTES];
+            public_key.copy_from_slice(&bytes);
+            Ok((peer_id, PublicSignKey::from_bytes(public_key)))
+        })
+        .collect()
+}
+
+/// Decode a hex encoded key of `len` bytes
+fn decode_key(encoded: &str, len: usize) -> Result<Vec<u8>, String> {
+    let bytes = hex::decode(encoded).map_err(|err| format!("{:?}", err))?;
+    if bytes.len() != len {
+        return Err(format!("expected {} bytes, got {}", len, bytes.len()));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn generated_key_reloads_and_verifies() {
+        let path = env::temp_dir().join(format!("snowball-signing-key-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let generated = load_or_create_signing_key(&path).unwrap();
+        let reloaded = load_or_create_signing_key(&path).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let key_file: KeyFile = serde_json::from_str(&contents).unwrap();
+
+        let peer_id: PeerId = vec![1, 2, 3];
+        let setting = format!(r#"{{"{}": "{}"}}"#, hex::encode(&peer_id), key_file.public_key);
+        let public_keys = get_public_keys_from_setting(&setting).unwrap();
+
+        let data = b"snowball message";
+        let signature = MessageSigner::new(Some(reloaded), HashMap::new()).sign(data);
+        assert_eq!(signature, MessageSigner::new(Some(generated), HashMap::new()).sign(data));
+
+        let verifier = MessageSigner::new(None, public_keys);
+        assert!(verifier.enforced());
+        assert!(verifier.verify(&peer_id, data, &signature).is_ok());
+        assert!(verifier.verify(&peer_id, b"tampered message", &signature).is_err());
+        assert!(verifier.verify(&vec![4, 5, 6], data, &signature).is_err());
+        assert!(verifier.verify(&peer_id, data, &signature[1..]).is_err());
+    }
+}
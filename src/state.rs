@@ -1,29 +1,59 @@
 use std::fmt;
 use std::iter::FromIterator;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
 
 use crate::timing::Timeout;
-use crate::config::SnowballConfig;
+use crate::config::{SnowballConfig, ProposerSchedule, SnowballVariant, EscalationPolicy};
+use crate::message::{FinalityCertificate, QueryId};
+
+/// Maximum number of abandoned queries remembered per instance to recognize
+/// late answers
+const MAX_LATE_QUERIES: usize = 256;
 
 /// Phases of the Snowball algorithm
 #[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
 pub enum SnowballPhase {
     Idle,
+    Validating,
     Listening,
     Finishing,
 }
 
-/// Decision states of the Snowball algorithm
-#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize, Copy)]
+/// Decision states of the Snowball algorithm. Every candidate block of an
+/// instance is a color of its own, while `KO` rejects all of them.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Clone, Serialize, Deserialize)]
 pub enum SnowballDecisionState {
-    OK,
+    OK(BlockId),
     KO,
     Undecided
 }
 
+impl SnowballDecisionState {
+    /// Decode the color carried by the vote of a peer message. An abstention
+    /// decodes as `Undecided`.
+    pub fn from_vote(vote: u8, block_id: BlockId) -> Option<Self> {
+        match vote {
+            0 => Some(SnowballDecisionState::KO),
+            1 => Some(SnowballDecisionState::OK(block_id)),
+            2 => Some(SnowballDecisionState::Undecided),
+            _ => None,
+        }
+    }
+
+    /// Encode this color as the vote and block id of a peer message. An
+    /// undecided node cannot vote for any candidate, so it abstains.
+    pub fn to_vote(&self) -> (u8, BlockId) {
+        match self {
+            SnowballDecisionState::OK(block_id) => (1, block_id.clone()),
+            SnowballDecisionState::KO => (0, BlockId::new()),
+            SnowballDecisionState::Undecided => (2, BlockId::new()),
+        }
+    }
+}
+
 impl fmt::Display for SnowballPhase {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -31,6 +61,7 @@ impl fmt::Display for SnowballPhase {
             "{}",
             match self {
                 SnowballPhase::Idle => "Idle",
+                SnowballPhase::Validating => "Validating",
                 SnowballPhase::Listening => "Listening",
                 SnowballPhase::Finishing => "Finishing",
             },
@@ -44,21 +75,36 @@ impl fmt::Display for SnowballDecisionState {
             f,
             "{}",
             match self {
-                SnowballDecisionState::OK => "OK",
-                SnowballDecisionState::KO => "KO",
-                SnowballDecisionState::Undecided => "Undecided",
+                SnowballDecisionState::OK(block_id) => format!("OK({})", hex::encode(block_id)),
+                SnowballDecisionState::KO => String::from("KO"),
+                SnowballDecisionState::Undecided => String::from("Undecided"),
             },
         )
     }
 }
 
+impl fmt::Display for SnowballInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "(seq {}, {}, waiting_set: {:?}, response_buffer: {:?})",
+            self.seq_num, self.phase, self.waiting_response_map, self.response_buffer
+        )
+    }
+}
+
 impl fmt::Display for SnowballState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instances: Vec<String> = self.instances.values().map(|i| i.to_string()).collect();
+        let peers: Vec<String> = self.peer_stats
+            .iter()
+            .map(|(peer_id, stats)| format!("{}: {:.3} {}", hex::encode(peer_id), self.reputation(peer_id), stats))
+            .collect();
         write!(
             f,
-            "(process {}, {}, seq {}, chain head: {:?}, waiting_set: {:?}, response_buffer: {:?}, byzantine: {:?})",
-            self.order, self.phase, self.seq_num, hex::encode(&self.chain_head), self.waiting_response_map,
-            self.response_buffer, self.byzantine_test
+            "(process {}, seq {}, view {}, chain head: {:?}, instances: [{}], peers: [{}], byzantine: {:?})",
+            self.order, self.seq_num, self.view, hex::encode(&self.chain_head), instances.join(", "),
+            peers.join(", "), self.byzantine_test
         )
     }
 }
@@ -90,17 +136,119 @@ pub struct Measurements {
     pub convergenza: HashMap<BlockId, u128>,
 
     pub n_messaggi_inviati: u64,
+
+    /// Messages of a type unknown to this version of the engine
+    pub unknown_messages: u64,
+
+    /// Messages dropped because their nonce was already received
+    pub replayed_messages: u64,
 }
 
 impl Measurements {
     pub fn new() -> Self {
         Measurements {
             convergenza: HashMap::new(),
-            n_messaggi_inviati: 0
+            n_messaggi_inviati: 0,
+            unknown_messages: 0,
+            replayed_messages: 0,
         }
     }
 }
 
+/// Query waiting for the answer of a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingQuery {
+    pub query: QueryId,
+    /// Started when the query was sent
+    pub timeout: Timeout,
+}
+
+/// Sampling and threshold parameters of an instance
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SnowballParameters {
+    pub k: u64,
+    pub alfa_preference: u64,
+    pub alfa_confidence: u64,
+    pub beta: u64,
+}
+
+/// What a node observed about the behaviour of a single peer
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PeerStats {
+    /// Responses received in time
+    pub responses: u64,
+
+    /// Sum of the latencies of the responses received in time
+    pub total_latency: Duration,
+
+    /// Queries which expired without a response
+    pub timeouts: u64,
+
+    /// Queries which expired since the last response
+    pub consecutive_timeouts: u64,
+
+    /// Responses carrying a vote which couldn't be decoded
+    pub invalid_votes: u64,
+
+    /// Messages about sequence numbers the peer couldn't have been asked about
+    pub spurious_seq_nums: u64,
+
+    /// Blocks proposed by the peer with a malformed consensus field
+    pub malformed_blocks: u64,
+
+    /// Messages from the peer whose signature couldn't be verified
+    pub invalid_signatures: u64,
+
+    /// Answers to queries which had expired or belonged to an earlier round
+    pub late_responses: u64,
+
+    /// Sum of the latencies of the late answers
+    pub total_late_latency: Duration,
+}
+
+impl PeerStats {
+    pub fn mean_latency(&self) -> Duration {
+        if self.responses == 0 {
+            return Duration::from_millis(0);
+        }
+        self.total_latency / self.responses as u32
+    }
+
+    pub fn mean_late_latency(&self) -> Duration {
+        if self.late_responses == 0 {
+            return Duration::from_millis(0);
+        }
+        self.total_late_latency / self.late_responses as u32
+    }
+
+    /// Sampling weight of the peer in (0, 1]. It halves with every timeout
+    /// since the last response, and shrinks with slow responses and with each
+    /// invalid vote, spurious message, malformed block or invalid signature.
+    pub fn score(&self, hang_timeout: Duration) -> f64 {
+        let unresponsiveness = 0.5f64.powi(self.consecutive_timeouts.min(32) as i32);
+        let slowness = 1.0 + duration_ratio(self.mean_latency(), hang_timeout);
+        let misbehaviour =
+            1.0 + (self.invalid_votes + self.spurious_seq_nums + self.malformed_blocks + self.invalid_signatures) as f64;
+        unresponsiveness / (slowness * misbehaviour)
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "(responses {}, mean latency {:?}, timeouts {}, late {}, mean late latency {:?}, invalid votes {}, spurious {}, malformed blocks {}, invalid signatures {})",
+            self.responses, self.mean_latency(), self.timeouts, self.late_responses, self.mean_late_latency(),
+            self.invalid_votes, self.spurious_seq_nums, self.malformed_blocks, self.invalid_signatures
+        )
+    }
+}
+
+fn duration_ratio(numerator: Duration, denominator: Duration) -> f64 {
+    let denominator = denominator.as_millis().max(1) as f64;
+    numerator.as_millis() as f64 / denominator
+}
+
 impl ByzantineParameters {
     pub fn new(config: &SnowballConfig) -> Self {
         ByzantineParameters {
@@ -118,6 +266,147 @@ impl ByzantineParameters {
 }
 
 
+/// Information about a single Snowball instance, deciding among the candidate
+/// blocks proposed for one sequence number
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnowballInstance {
+    /// Sequence number decided by this instance
+    pub seq_num: u64,
+
+    /// Current phase of the instance
+    pub phase: SnowballPhase,
+
+    // Last color
+    pub last_color: SnowballDecisionState,
+
+    // Confidence counter
+    pub confidence_counter: u64,
+
+    /// Parameters in use, fixed when the instance starts
+    pub params: SnowballParameters,
+
+    /// Number of completed query rounds
+    pub round: u64,
+
+    /// Times the instance reached the maximum number of rounds
+    pub escalations: u64,
+
+    // Response buffer, counting the votes of the current round for each color
+    pub response_buffer: HashMap<SnowballDecisionState, u64>,
+
+    /// Peers which answered in the current round
+    pub responders: Vec<PeerId>,
+
+    /// Tips preferred by the peers which answered in the current round
+    pub tips: Vec<BlockId>,
+
+    /// Peers which abstained in the current round
+    pub abstentions: u64,
+
+    /// Peers which abstained over all the rounds
+    pub total_abstentions: u64,
+
+    // Decision array, counting the successful rounds of each color
+    pub decision_array: HashMap<SnowballDecisionState, u64>,
+
+    // Set containing ids from peers we're waiting response
+    pub waiting_response_map: HashMap<PeerId, PendingQuery>,
+
+    /// Poll rounds started so far, never reset; identifies the current round
+    pub round_id: u64,
+
+    /// Queries sent so far
+    pub query_count: u64,
+
+    /// Peers queried in the current round. Whatever they answered, or if they
+    /// didn't, they aren't queried again before the round ends.
+    pub queried_peers: HashSet<PeerId>,
+
+    /// Queries abandoned because they expired or their round ended, indexed
+    /// by query id
+    pub late_queries: BTreeMap<u64, (PeerId, PendingQuery)>,
+
+    /// Competing blocks of the instance, in order of arrival
+    pub candidate_blocks: Vec<BlockId>,
+
+    /// Previous block id of each candidate
+    pub candidate_parents: HashMap<BlockId, BlockId>,
+
+    /// Block number shared by the candidates of the instance
+    pub decision_block_num: u64,
+
+    /// Deadline for the validator's verdicts, after which the node abstains
+    pub validation_timeout: Timeout,
+}
+
+impl SnowballInstance {
+    pub fn new(seq_num: u64, block_num: u64, params: SnowballParameters, validation_timeout: Duration) -> Self {
+        SnowballInstance {
+            seq_num,
+            params,
+            phase: SnowballPhase::Idle,
+            last_color: SnowballDecisionState::Undecided,
+            confidence_counter: 0,
+            round: 0,
+            escalations: 0,
+            responders: Vec::new(),
+            tips: Vec::new(),
+            abstentions: 0,
+            total_abstentions: 0,
+            response_buffer: HashMap::new(),
+            decision_array: HashMap::new(),
+            waiting_response_map: HashMap::new(),
+            round_id: 0,
+            query_count: 0,
+            queried_peers: HashSet::new(),
+            late_queries: BTreeMap::new(),
+            candidate_blocks: Vec::new(),
+            candidate_parents: HashMap::new(),
+            decision_block_num: block_num,
+            validation_timeout: Timeout::new(validation_timeout),
+        }
+    }
+
+    /// Stop waiting for the answer of `peer_id`, which only counts as a late
+    /// arrival from now on
+    pub fn abandon_query(&mut self, peer_id: &PeerId) {
+        if let Some(pending) = self.waiting_response_map.remove(peer_id) {
+            self.late_queries.insert(pending.query.query_id, (peer_id.clone(), pending));
+            while self.late_queries.len() > MAX_LATE_QUERIES {
+                let oldest = *self.late_queries.keys().next().unwrap();
+                self.late_queries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Stop waiting for every answer still outstanding
+    pub fn abandon_queries(&mut self) {
+        let peer_ids: Vec<PeerId> = self.waiting_response_map.keys().cloned().collect();
+        for peer_id in peer_ids {
+            self.abandon_query(&peer_id);
+        }
+    }
+
+    /// Block which the candidates of the instance build on
+    pub fn previous_id(&self) -> Option<&BlockId> {
+        self.candidate_blocks
+            .first()
+            .and_then(|block_id| self.candidate_parents.get(block_id))
+    }
+
+    pub fn switch_phase(&mut self) {
+        let next_phase = match self.phase {
+            SnowballPhase::Idle => SnowballPhase::Validating,
+            SnowballPhase::Validating => SnowballPhase::Listening,
+            SnowballPhase::Listening => SnowballPhase::Finishing,
+            SnowballPhase::Finishing => SnowballPhase::Idle
+        };
+
+        info!("Switching phase {} to {} for seq_num {}", self.phase, next_phase, self.seq_num);
+        self.phase = next_phase;
+    }
+}
+
 /// Information about the Snowball algorithm's state
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SnowballState {
@@ -127,48 +416,97 @@ pub struct SnowballState {
     /// This node order number in the member array
     pub order: u64,
 
-    /// The node's current sequence number
+    /// The sequence number of the last instance started by the node
     pub seq_num: u64,
 
     // Alfa parameter
     pub alfa: u64,
 
+    /// Votes needed in a round to change the preferred color
+    pub alfa_preference: u64,
+
+    /// Votes needed in a round to increase the confidence in a color
+    pub alfa_confidence: u64,
+
     // Beta parameter
     pub beta: u64,
 
     // Sample size
     pub k: u64,
 
-    // Current color
-    pub decision_map: HashMap<u64, SnowballDecisionState>,
+    /// Recompute k, alfa and beta from the membership size for each instance
+    pub adaptive: bool,
 
-    // Last color
-    pub last_color: SnowballDecisionState,
+    pub adaptive_k_min: u64,
+    pub adaptive_k_max: u64,
+    pub adaptive_beta_min: u64,
+    pub adaptive_beta_max: u64,
 
-    // Confidence counter
-    pub confidence_counter: u64,
+    /// Update rule applied at the end of each query round
+    pub variant: SnowballVariant,
 
-    // Response buffer
-    pub response_buffer: [u64; 2],
+    /// Maximum number of instances running at the same time
+    pub window: u64,
 
-    // Decision array
-    pub decision_array: [u64; 2],
+    /// Rounds after which an undecided instance is escalated, 0 for no limit
+    pub max_rounds: u64,
 
-    // Set containing ids from peers we're waiting response
-    pub waiting_response_map: HashMap<PeerId, Timeout>,
+    /// How instances reaching `max_rounds` are decided
+    pub escalation: EscalationPolicy,
+
+    // Current color of each instance
+    pub decision_map: HashMap<u64, SnowballDecisionState>,
+
+    /// Running instances, indexed by sequence number. Decided instances wait
+    /// in the `Finishing` phase until all the previous ones are committed
+    pub instances: BTreeMap<u64, SnowballInstance>,
 
     /// The block ID of the node's current chain head
     pub chain_head: BlockId,
 
-    /// Block id to accept
-    pub decision_block: BlockId,
+    /// The block number of the node's current chain head
+    pub chain_head_block_num: u64,
 
-    /// Current phase of the algorithm
-    pub phase: SnowballPhase,
+    /// Verdicts received from the validator for the candidate blocks
+    pub block_validation: HashMap<BlockId, bool>,
+
+    /// Recently failed blocks with their block number, so that blocks built on
+    /// top of them can be failed as well
+    pub failed_blocks: HashMap<BlockId, u64>,
+
+    /// How long to wait for the validator to judge a block before abstaining
+    pub validation_timeout: Duration,
 
     /// List of members in the Snowball network, including this node
     pub member_ids: Vec<PeerId>,
 
+    /// Members listed in the on-chain setting. Unlike `member_ids`, they don't
+    /// change with peer connections, so every node derives the same proposer
+    /// schedule from them.
+    pub members: Vec<PeerId>,
+
+    /// Sampling weight of each member when sampling by stake
+    pub stake: HashMap<PeerId, u64>,
+
+    /// Behaviour observed for each peer, used to rate its reputation
+    pub peer_stats: HashMap<PeerId, PeerStats>,
+
+    /// How the proposer of each instance is chosen among the members
+    pub proposer_schedule: ProposerSchedule,
+
+    /// Evidence of the decisions taken by this node which no committed block
+    /// carries yet, embedded in every block this node publishes
+    pub pending_certificates: BTreeMap<u64, FinalityCertificate>,
+
+    /// Offset applied to the proposer schedule, increased by one every time the
+    /// members agree to replace a proposer which stopped producing blocks and
+    /// reset once a block is committed
+    pub view: u64,
+    /// Proposers replaced by the members, with the sequence number they were
+    /// replaced at. They are left out of the schedule for a full rotation, so
+    /// that a crashed proposer doesn't stall every instance it is due for.
+    pub replaced_proposers: HashMap<PeerId, u64>,
+
     /// The base time to use for retrying with exponential backoff
     pub exponential_retry_base: Duration,
 
@@ -200,18 +538,34 @@ impl SnowballState {
             order: order,
             seq_num: head_block_num + 1,
             alfa: config.alfa,
+            alfa_preference: config.alfa_preference,
+            alfa_confidence: config.alfa_confidence,
             beta: config.beta,
             k: config.k,
+            adaptive: config.adaptive,
+            adaptive_k_min: config.adaptive_k_min,
+            adaptive_k_max: config.adaptive_k_max,
+            adaptive_beta_min: config.adaptive_beta_min,
+            adaptive_beta_max: config.adaptive_beta_max,
+            variant: config.variant,
+            window: config.window,
+            max_rounds: config.max_rounds,
+            escalation: config.escalation,
             decision_map: decision_map,
-            last_color: SnowballDecisionState::Undecided,
-            confidence_counter: 0,
-            response_buffer: [0, 0],
-            decision_array: [0, 0],
-            waiting_response_map: HashMap::new(),
+            instances: BTreeMap::new(),
             chain_head: BlockId::new(),
-            decision_block: BlockId::new(),
-            phase: SnowballPhase::Idle,
+            chain_head_block_num: head_block_num,
+            block_validation: HashMap::new(),
+            failed_blocks: HashMap::new(),
+            validation_timeout: config.validation_timeout,
             member_ids: config.members.clone(),
+            members: config.members.clone(),
+            stake: config.stake.clone(),
+            peer_stats: HashMap::new(),
+            proposer_schedule: config.proposer_schedule,
+            view: 0,
+            replaced_proposers: HashMap::new(),
+            pending_certificates: BTreeMap::new(),
             exponential_retry_base: config.exponential_retry_base,
             exponential_retry_max: config.exponential_retry_max,
             hang_timeout: config.hang_timeout,
@@ -220,25 +574,253 @@ impl SnowballState {
         }
     }
 
-    pub fn switch_phase(&mut self) {
-        let next_phase = match self.phase {
-            SnowballPhase::Idle => SnowballPhase::Listening,
-            SnowballPhase::Listening => SnowballPhase::Finishing,
-            SnowballPhase::Finishing => SnowballPhase::Idle
+    /// Parameters for an instance starting now. In adaptive mode k is bounded
+    /// by the number of peers, both alfas keep their configured ratio to k
+    /// while staying a strict majority, and beta grows with the bit length of
+    /// the number of peers.
+    pub fn parameters(&self) -> SnowballParameters {
+        if !self.adaptive {
+            return SnowballParameters {
+                k: self.k,
+                alfa_preference: self.alfa_preference,
+                alfa_confidence: self.alfa_confidence,
+                beta: self.beta,
+            };
+        }
+
+        let peers = (self.member_ids.len() as u64).saturating_sub(1);
+        let k = peers.min(self.adaptive_k_max).max(self.adaptive_k_min).min(peers);
+        let configured_k = self.k.max(1);
+        let scale = |alfa: u64| {
+            let scaled = (alfa * k + configured_k - 1) / configured_k;
+            scaled.max(k / 2 + 1).min(k)
         };
+        let bit_length = u64::from(64 - peers.leading_zeros());
 
-        info!("Switching phase {} to {}", self.phase, next_phase);
-        self.phase = next_phase;
+        SnowballParameters {
+            k,
+            alfa_preference: scale(self.alfa_preference),
+            alfa_confidence: scale(self.alfa_confidence),
+            beta: bit_length.max(self.adaptive_beta_min).min(self.adaptive_beta_max),
+        }
+    }
+
+    /// Reputation of a member in (0, 1], 1 for peers which never misbehaved
+    pub fn reputation(&self, peer_id: &PeerId) -> f64 {
+        self.peer_stats
+            .get(peer_id)
+            .map_or(1.0, |stats| stats.score(self.hang_timeout))
+    }
+
+    pub fn record_response(&mut self, peer_id: &PeerId, latency: Duration) {
+        let stats = self.peer_stats.entry(peer_id.clone()).or_default();
+        stats.responses += 1;
+        stats.total_latency += latency;
+        stats.consecutive_timeouts = 0;
+    }
+
+    pub fn record_late_response(&mut self, peer_id: &PeerId, latency: Duration) {
+        let stats = self.peer_stats.entry(peer_id.clone()).or_default();
+        stats.late_responses += 1;
+        stats.total_late_latency += latency;
+    }
+
+    pub fn record_timeout(&mut self, peer_id: &PeerId) {
+        let stats = self.peer_stats.entry(peer_id.clone()).or_default();
+        stats.timeouts += 1;
+        stats.consecutive_timeouts += 1;
+    }
+
+    pub fn record_invalid_vote(&mut self, peer_id: &PeerId) {
+        self.peer_stats.entry(peer_id.clone()).or_default().invalid_votes += 1;
+    }
+
+    pub fn record_spurious_seq_num(&mut self, peer_id: &PeerId) {
+        self.peer_stats.entry(peer_id.clone()).or_default().spurious_seq_nums += 1;
+    }
+
+    pub fn record_malformed_block(&mut self, peer_id: &PeerId) {
+        self.peer_stats.entry(peer_id.clone()).or_default().malformed_blocks += 1;
+    }
+
+    pub fn record_invalid_signature(&mut self, peer_id: &PeerId) {
+        self.peer_stats.entry(peer_id.clone()).or_default().invalid_signatures += 1;
     }
 
     pub fn get_order_index(&mut self, id: PeerId) -> u64 {
         self.member_ids.clone().iter().position(|x| x == &id).unwrap() as u64
     }
 
-    pub fn add_to_waiting_set(&mut self, id: PeerId) {
+    /// Compute the member expected to propose the block for instance `seq_num`, built on top of
+    /// `previous_id`. Every node evaluates the same schedule, so the result can also be used to
+    /// verify the signer of an incoming block.
+    pub fn get_proposer(&self, seq_num: u64, previous_id: &BlockId) -> PeerId {
+        self.schedule(seq_num, previous_id, self.view, None)
+    }
+
+    /// Compute the proposer of instance `seq_num` once the members agree to
+    /// replace the current one
+    pub fn get_next_proposer(&self, seq_num: u64, previous_id: &BlockId) -> PeerId {
+        let current = self.get_proposer(seq_num, previous_id);
+        self.schedule(seq_num, previous_id, self.view + 1, Some(&current))
+    }
+
+    /// Move to the next view of instance `seq_num`, leaving its current
+    /// proposer out of the schedule for a while
+    pub fn replace_proposer(&mut self, seq_num: u64, previous_id: &BlockId) {
+        let current = self.get_proposer(seq_num, previous_id);
+        let rotation = self.members.len() as u64;
+        self.replaced_proposers.retain(|_, replaced_at| *replaced_at + rotation > seq_num);
+        self.replaced_proposers.insert(current, seq_num);
+        self.view += 1;
+    }
+
+    /// Proposer of instance `seq_num` in `view`, among the members which
+    /// weren't replaced recently and aren't `skipped`
+    fn schedule(&self, seq_num: u64, previous_id: &BlockId, view: u64, skipped: Option<&PeerId>) -> PeerId {
+        let slot = match self.proposer_schedule {
+            ProposerSchedule::RoundRobin => seq_num,
+            ProposerSchedule::PreviousBlockHash => previous_id
+                .iter()
+                .take(8)
+                .fold(0u64, |acc, b| (acc << 8) | u64::from(*b)),
+        };
+
+        let rotation = self.members.len() as u64;
+        let eligible: Vec<&PeerId> = self.members
+            .iter()
+            .filter(|member| Some(*member) != skipped)
+            .filter(|member| match self.replaced_proposers.get(*member) {
+                Some(replaced_at) => *replaced_at > seq_num || *replaced_at + rotation <= seq_num,
+                None => true,
+            })
+            .collect();
+        // Every member was replaced recently, the plain schedule applies
+        if eligible.is_empty() {
+            let index = slot.wrapping_add(view) % rotation;
+            return self.members[index as usize].clone();
+        }
+
+        let index = slot.wrapping_add(view) % eligible.len() as u64;
+        eligible[index as usize].clone()
+    }
+
+    /// Whether `peer_id` is listed in the on-chain members setting
+    pub fn is_member(&self, peer_id: &PeerId) -> bool {
+        self.members.contains(peer_id)
+    }
+
+    pub fn is_proposer(&self, seq_num: u64, previous_id: &BlockId) -> bool {
+        self.get_proposer(seq_num, previous_id) == self.id
+    }
+
+    /// Whether `color` can be adopted as preference for instance `seq_num`.
+    /// Blocks rejected by the validator are never adopted.
+    pub fn is_candidate(&self, seq_num: u64, color: &SnowballDecisionState) -> bool {
+        let instance = match self.instances.get(&seq_num) {
+            Some(instance) => instance,
+            None => return false,
+        };
+        match color {
+            SnowballDecisionState::OK(block_id) => {
+                instance.candidate_blocks.contains(block_id)
+                    && self.block_validation.get(block_id) != Some(&false)
+            }
+            SnowballDecisionState::KO => true,
+            SnowballDecisionState::Undecided => false,
+        }
+    }
+
+    /// The lowest sequence number this node hasn't decided yet
+    pub fn first_undecided_seq_num(&self) -> u64 {
+        self.instances.keys().next().cloned().unwrap_or(self.seq_num + 1)
+    }
+
+    /// Block the next proposal should build on: the preferred block of the
+    /// newest instance, or the chain head when no instance is running. `None`
+    /// while the newest instance doesn't prefer any block.
+    pub fn get_tip(&self) -> Option<BlockId> {
+        match self.instances.keys().next_back() {
+            Some(seq_num) => match self.decision_map.get(seq_num) {
+                Some(SnowballDecisionState::OK(block_id)) => Some(block_id.clone()),
+                _ => None,
+            },
+            None => Some(self.chain_head.clone()),
+        }
+    }
+
+    /// Newest block preferred by a running instance, or the chain head
+    pub fn preferred_tip(&self) -> BlockId {
+        self.instances
+            .keys()
+            .rev()
+            .filter_map(|seq_num| match self.decision_map.get(seq_num) {
+                Some(SnowballDecisionState::OK(block_id)) => Some(block_id.clone()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or_else(|| self.chain_head.clone())
+    }
+
+    /// Running instances on the chain ending with `block_id`, each with its
+    /// candidate on that chain
+    pub fn ancestors(&self, block_id: &BlockId) -> Vec<(u64, BlockId)> {
+        let mut ancestors = Vec::new();
+        let mut current = block_id.clone();
+        while let Some(instance) = self.instances.values().find(|instance| instance.candidate_blocks.contains(&current)) {
+            ancestors.push((instance.seq_num, current.clone()));
+            current = match instance.candidate_parents.get(&current) {
+                Some(previous_id) => previous_id.clone(),
+                None => break,
+            };
+        }
+        ancestors
+    }
+
+    /// Wait for the answer of `id` to a new query in the current round of
+    /// instance `seq_num`, returning the id the answer must echo. A query
+    /// still outstanding for the same peer is abandoned.
+    pub fn add_to_waiting_set(&mut self, seq_num: u64, id: PeerId) -> Option<QueryId> {
         let mut timeout = Timeout::new(self.hang_timeout);
         timeout.start();
-        self.waiting_response_map.insert(id, timeout);
+        let instance = self.instances.get_mut(&seq_num)?;
+        instance.abandon_query(&id);
+        instance.queried_peers.insert(id.clone());
+
+        instance.query_count += 1;
+        let query = QueryId {
+            round_id: instance.round_id,
+            query_id: instance.query_count,
+        };
+        instance.waiting_response_map.insert(id, PendingQuery { query, timeout });
+        Some(query)
+    }
+
+    /// Match an answer of `peer_id` about instance `seq_num` with the query it
+    /// echoes. Returns the latency of the query when the answer is awaited;
+    /// answers to abandoned queries are only recorded as late arrivals.
+    /// Queries are numbered from 1: answers from nodes which predate query ids
+    /// carry id 0 and are never matched, since they can't be told apart from
+    /// stale ones.
+    pub fn take_response(&mut self, seq_num: u64, peer_id: &PeerId, query: QueryId) -> Option<Duration> {
+        let instance = self.instances.get_mut(&seq_num)?;
+        let awaited = match instance.waiting_response_map.get(peer_id) {
+            Some(pending) => pending.query == query,
+            None => false,
+        };
+        if awaited {
+            return instance.waiting_response_map.remove(peer_id).map(|pending| pending.timeout.elapsed());
+        }
+
+        let late = match instance.late_queries.get(&query.query_id) {
+            Some((late_peer_id, pending)) => late_peer_id == peer_id && pending.query == query,
+            None => false,
+        };
+        if late {
+            let (_, pending) = instance.late_queries.remove(&query.query_id).unwrap();
+            self.record_late_response(peer_id, pending.timeout.elapsed());
+        }
+        None
     }
 
     pub fn set_message_sent(&mut self) {
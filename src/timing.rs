@@ -74,6 +74,11 @@ impl Timeout {
         self.start = Instant::now();
     }
 
+    /// Time passed since the timer was last started or stopped
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - self.start
+    }
+
     pub fn is_active(&self) -> bool {
         self.state == TimeoutState::Active
     }